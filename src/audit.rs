@@ -0,0 +1,427 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::db::Item;
+use crate::dbo::{delete_item, get_item, insert_item, update_item, version, DBObject};
+use crate::error::ServiceError;
+use crate::notify;
+use crate::trash;
+
+const CREATE_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS audit_log (
+        id BIGSERIAL PRIMARY KEY,
+        user_id BIGINT NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        entity TEXT NOT NULL,
+        item_id BIGINT NOT NULL,
+        command TEXT NOT NULL,
+        before JSONB,
+        after JSONB
+    );
+    CREATE INDEX IF NOT EXISTS audit_log_item_idx ON audit_log (entity, item_id);
+    CREATE INDEX IF NOT EXISTS audit_log_created_at_idx ON audit_log (created_at);
+    CREATE OR REPLACE RULE audit_log_no_update AS ON UPDATE TO audit_log DO INSTEAD NOTHING;
+    CREATE OR REPLACE RULE audit_log_no_delete AS ON DELETE TO audit_log DO INSTEAD NOTHING;
+";
+
+const DEFAULT_LIMIT: i64 = 100;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditFilter {
+    pub entity: Option<String>,
+    pub user_id: Option<i64>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub user_id: i64,
+    pub created_at: DateTime<Utc>,
+    pub entity: String,
+    pub item_id: i64,
+    pub command: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+/// How a field changed between two versions of an item.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct FieldChange {
+    pub from: Value,
+    pub to: Value,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub user_id: i64,
+    pub created_at: DateTime<Utc>,
+    pub command: String,
+    pub changes: BTreeMap<String, FieldChange>,
+}
+
+pub async fn init(client: &Client) -> Result<(), ServiceError> {
+    client.batch_execute(CREATE_TABLE).await?;
+    Ok(())
+}
+
+pub async fn get_list(filter: &AuditFilter, client: &Client) -> Result<DBObject, ServiceError> {
+    let rows = client
+        .query(
+            "SELECT id, user_id, created_at, entity, item_id, command, before, after
+            FROM audit_log
+            WHERE ($1::text IS NULL OR entity = $1)
+                AND ($2::bigint IS NULL OR user_id = $2)
+                AND ($3::timestamptz IS NULL OR created_at >= $3)
+                AND ($4::timestamptz IS NULL OR created_at < $4)
+            ORDER BY id DESC
+            LIMIT $5",
+            &[
+                &filter.entity,
+                &filter.user_id,
+                &filter.from,
+                &filter.to,
+                &filter.limit.unwrap_or(DEFAULT_LIMIT),
+            ],
+        )
+        .await?;
+    Ok(DBObject::AuditList(
+        rows.iter()
+            .map(|row| AuditEntry {
+                id: row.get(0),
+                user_id: row.get(1),
+                created_at: row.get(2),
+                entity: row.get(3),
+                item_id: row.get(4),
+                command: row.get(5),
+                before: row.get(6),
+                after: row.get(7),
+            })
+            .collect(),
+    ))
+}
+
+/// Fields of a `DBObject` snapshot such as `{"Contact": {...}}`.
+fn fields(snapshot: &Option<Value>) -> serde_json::Map<String, Value> {
+    snapshot
+        .as_ref()
+        .and_then(Value::as_object)
+        .and_then(|object| object.values().next())
+        .and_then(Value::as_object)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Fields that differ between two snapshots. A missing snapshot, before an
+/// insert or after a delete, counts as all fields being null.
+pub fn diff(before: &Option<Value>, after: &Option<Value>) -> BTreeMap<String, FieldChange> {
+    let before = fields(before);
+    let after = fields(after);
+    before
+        .keys()
+        .chain(after.keys())
+        .filter_map(|key| {
+            let from = before.get(key).cloned().unwrap_or(Value::Null);
+            let to = after.get(key).cloned().unwrap_or(Value::Null);
+            if from == to {
+                None
+            } else {
+                Some((key.clone(), FieldChange { from, to }))
+            }
+        })
+        .collect()
+}
+
+/// Versions of an item from the oldest to the newest.
+pub async fn history(item: &Item, client: &Client) -> Result<DBObject, ServiceError> {
+    let rows = client
+        .query(
+            "SELECT id, user_id, created_at, command, before, after
+            FROM audit_log
+            WHERE entity = $1 AND item_id = $2
+            ORDER BY id",
+            &[&item.name, &item.id],
+        )
+        .await?;
+    Ok(DBObject::History(
+        rows.iter()
+            .map(|row| HistoryEntry {
+                id: row.get(0),
+                user_id: row.get(1),
+                created_at: row.get(2),
+                command: row.get(3),
+                changes: diff(&row.get(4), &row.get(5)),
+            })
+            .collect(),
+    ))
+}
+
+/// An item as it was at `at`: the state after the last change made until
+/// then, or else the state before the first change made since. An item that
+/// was not changed since is returned as it is now.
+pub async fn get_item_at(
+    item: &Item,
+    at: &DateTime<Utc>,
+    client: &Client,
+) -> Result<DBObject, ServiceError> {
+    let last = client
+        .query_opt(
+            "SELECT after FROM audit_log
+            WHERE entity = $1 AND item_id = $2 AND created_at <= $3
+            ORDER BY id DESC LIMIT 1",
+            &[&item.name, &item.id, at],
+        )
+        .await?;
+    let next = match last {
+        Some(_) => None,
+        None => {
+            client
+                .query_opt(
+                    "SELECT before FROM audit_log
+                    WHERE entity = $1 AND item_id = $2 AND created_at > $3
+                    ORDER BY id LIMIT 1",
+                    &[&item.name, &item.id, at],
+                )
+                .await?
+        }
+    };
+    let state: Option<Value> = match last.or(next) {
+        Some(row) => row.get(0),
+        None => return get_item(item, client).await,
+    };
+    match state {
+        Some(value) => Ok(serde_json::from_value(value)?),
+        None => Err(ServiceError::BadRequest(format!(
+            "{} {} did not exist at {}",
+            item.name, item.id, at
+        ))),
+    }
+}
+
+async fn snapshot(name: &str, id: i64, client: &Client) -> Result<Value, ServiceError> {
+    let item = Item {
+        name: name.to_string(),
+        id,
+    };
+    Ok(serde_json::to_value(get_item(&item, client).await?)?)
+}
+
+async fn record(
+    client: &Client,
+    user_id: i64,
+    entity: &str,
+    item_id: i64,
+    command: &str,
+    before: Option<Value>,
+    after: Option<Value>,
+) -> Result<(), ServiceError> {
+    client
+        .execute(
+            "INSERT INTO audit_log (user_id, entity, item_id, command, before, after)
+            VALUES ($1, $2, $3, $4, $5, $6)",
+            &[&user_id, &entity, &item_id, &command, &before, &after],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Commits or rolls back the transaction opened with `notify::begin` on `client`.
+/// rpel works on a plain `Client`, so the transaction is driven by hand on
+/// the same connection.
+pub async fn finish<T>(
+    client: &Client,
+    result: Result<T, ServiceError>,
+) -> Result<T, ServiceError> {
+    match result {
+        Ok(value) => {
+            client.batch_execute("COMMIT").await?;
+            Ok(value)
+        }
+        Err(err) => {
+            let _ = client.batch_execute("ROLLBACK").await;
+            Err(err)
+        }
+    }
+}
+
+/// The `logged_*` operations run inside a transaction opened by the caller.
+pub async fn logged_insert(
+    user_id: i64,
+    object: DBObject,
+    client: &Client,
+) -> Result<i64, ServiceError> {
+    let name = object.name();
+    let id = insert_item(object, client).await?;
+    let after = snapshot(&name, id, client).await?;
+    record(client, user_id, &name, id, "Insert", None, Some(after)).await?;
+    Ok(id)
+}
+
+/// Updates an item read at `expected` version.
+pub async fn logged_update(
+    user_id: i64,
+    object: DBObject,
+    expected: i64,
+    client: &Client,
+) -> Result<i64, ServiceError> {
+    let name = object.name();
+    let id = object
+        .id()
+        .ok_or_else(|| ServiceError::BadRequest("bad item object".to_string()))?;
+    let current = version(&name, id, true, client).await?;
+    if current.map_or(false, |current| current != expected) {
+        let object = snapshot(&name, id, client).await?;
+        return Err(ServiceError::Conflict(
+            json!({"version": current, "object": object}),
+        ));
+    }
+    let before = snapshot(&name, id, client).await?;
+    let res = update_item(object, client).await?;
+    let after = snapshot(&name, id, client).await?;
+    record(
+        client,
+        user_id,
+        &name,
+        id,
+        "Update",
+        Some(before),
+        Some(after),
+    )
+    .await?;
+    Ok(res)
+}
+
+pub async fn logged_delete(
+    user_id: i64,
+    item: &Item,
+    client: &Client,
+) -> Result<i64, ServiceError> {
+    let before = snapshot(&item.name, item.id, client).await?;
+    let res = if trash::soft_table(&item.name).is_some() {
+        trash::discard(item, user_id, client).await?
+    } else {
+        delete_item(item, client).await?
+    };
+    record(
+        client,
+        user_id,
+        &item.name,
+        item.id,
+        "Delete",
+        Some(before),
+        None,
+    )
+    .await?;
+    Ok(res)
+}
+
+pub async fn logged_restore(
+    user_id: i64,
+    item: &Item,
+    client: &Client,
+) -> Result<i64, ServiceError> {
+    let res = trash::restore(item, client).await?;
+    let after = snapshot(&item.name, item.id, client).await?;
+    record(
+        client,
+        user_id,
+        &item.name,
+        item.id,
+        "Restore",
+        None,
+        Some(after),
+    )
+    .await?;
+    Ok(res)
+}
+
+pub async fn logged_purge(user_id: i64, item: &Item, client: &Client) -> Result<i64, ServiceError> {
+    trash::check_discarded(item, client).await?;
+    let before = snapshot(&item.name, item.id, client).await?;
+    let res = delete_item(item, client).await?;
+    record(
+        client,
+        user_id,
+        &item.name,
+        item.id,
+        "Purge",
+        Some(before),
+        None,
+    )
+    .await?;
+    Ok(res)
+}
+
+pub async fn insert(user_id: i64, object: DBObject, client: &Client) -> Result<i64, ServiceError> {
+    notify::begin(client).await?;
+    let result = logged_insert(user_id, object, client).await;
+    finish(client, result).await
+}
+
+pub async fn update(
+    user_id: i64,
+    object: DBObject,
+    expected: i64,
+    client: &Client,
+) -> Result<i64, ServiceError> {
+    notify::begin(client).await?;
+    let result = logged_update(user_id, object, expected, client).await;
+    finish(client, result).await
+}
+
+pub async fn delete(user_id: i64, item: &Item, client: &Client) -> Result<i64, ServiceError> {
+    notify::begin(client).await?;
+    let result = logged_delete(user_id, item, client).await;
+    finish(client, result).await
+}
+
+pub async fn restore(user_id: i64, item: &Item, client: &Client) -> Result<i64, ServiceError> {
+    notify::begin(client).await?;
+    let result = logged_restore(user_id, item, client).await;
+    finish(client, result).await
+}
+
+pub async fn purge(user_id: i64, item: &Item, client: &Client) -> Result<i64, ServiceError> {
+    notify::begin(client).await?;
+    let result = logged_purge(user_id, item, client).await;
+    finish(client, result).await
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn diff_lists_changed_fields() {
+        let before = Some(json!({"Contact": {"id": 1, "name": "Ann", "note": null}}));
+        let after = Some(json!({"Contact": {"id": 1, "name": "Anna", "note": "moved"}}));
+        let changes = diff(&before, &after);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(
+            changes["name"],
+            FieldChange {
+                from: json!("Ann"),
+                to: json!("Anna")
+            }
+        );
+        assert_eq!(changes["note"].to, json!("moved"));
+    }
+
+    #[test]
+    fn diff_of_insert_starts_from_null() {
+        let after = Some(json!({"Rank": {"id": 2, "name": "Major"}}));
+        let changes = diff(&None, &after);
+        assert_eq!(changes["id"].from, Value::Null);
+        assert_eq!(changes["name"].to, json!("Major"));
+        assert!(diff(&after, &after).is_empty());
+    }
+}
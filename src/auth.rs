@@ -1,8 +1,15 @@
-use actix_web::{web, HttpResponse};
+use std::net::{IpAddr, SocketAddr};
+
+use actix::Addr;
+use actix_web::{web, HttpRequest, HttpResponse};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
-use crate::db::{get_reply, get_user, ClientMessage, Command};
+use crate::db::{get_reply, get_user, refresh_reply, ClientMessage, Command, UserData};
 use crate::error::ServiceError;
+use crate::server::{Revoke, Server};
+use crate::throttle;
+use crate::token;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Auth {
@@ -16,28 +23,146 @@ pub struct A {
     r: i64,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct T {
+    t: String,
+}
+
 #[derive(Serialize)]
 struct C {
     r: bool,
 }
 
-pub async fn login(data: web::Json<Auth>) -> Result<HttpResponse, ServiceError> {
-    let reply = get_reply(&data.u, &data.p).ok_or(ServiceError::NotAuth)?;
+/// Proxies whose `X-Forwarded-For` entries are believed, from the comma
+/// separated `TRUSTED_PROXIES` variable.
+static TRUSTED_PROXIES: Lazy<Vec<IpAddr>> = Lazy::new(|| {
+    dotenv::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect()
+});
+
+/// Address the login throttle counts against.
+fn client_ip(req: &HttpRequest) -> String {
+    let forwarded = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    real_ip(
+        req.peer_addr().map(|addr| addr.ip()),
+        &forwarded,
+        &TRUSTED_PROXIES,
+    )
+}
+
+/// Walks the hops from the peer back along `X-Forwarded-For` and stops at the
+/// first one that is not a trusted proxy. Entries left of it are written by
+/// the client and are never used.
+fn real_ip(peer: Option<IpAddr>, forwarded: &str, trusted: &[IpAddr]) -> String {
+    let mut hop = match peer {
+        Some(peer) => peer.to_string(),
+        None => return String::new(),
+    };
+    let mut entries = forwarded
+        .rsplit(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty());
+    while let Ok(ip) = hop.parse::<IpAddr>() {
+        if !trusted.contains(&ip) {
+            break;
+        }
+        hop = match entries.next() {
+            Some(entry) => entry
+                .parse::<SocketAddr>()
+                .map(|socket| socket.ip().to_string())
+                .unwrap_or_else(|_| entry.to_string()),
+            None => break,
+        };
+    }
+    hop
+}
+
+pub async fn login(req: HttpRequest, data: web::Json<Auth>) -> Result<HttpResponse, ServiceError> {
+    let keys = throttle::keys(&client_ip(&req), &data.u);
+    throttle::check(&keys)?;
+    let reply = match get_reply(&data.u, &data.p).await? {
+        Some(reply) => reply,
+        None => {
+            throttle::failure(&keys);
+            return Err(ServiceError::FailedAuth);
+        }
+    };
+    throttle::success(&data.u);
     Ok(HttpResponse::Ok().json(A {
         t: reply.0,
         r: reply.1,
     }))
 }
 
+/// Swaps a token for a new one. Sessions bound to the old token are closed,
+/// as with `logout`.
+pub async fn refresh(
+    data: web::Json<T>,
+    srv: web::Data<Addr<Server>>,
+) -> Result<HttpResponse, ServiceError> {
+    let reply = refresh_reply(&data.t).await?.ok_or(ServiceError::NotAuth)?;
+    srv.do_send(Revoke {
+        token: token::digest(&data.t),
+    });
+    Ok(HttpResponse::Ok().json(A {
+        t: reply.0,
+        r: reply.1,
+    }))
+}
+
+pub async fn logout(
+    data: web::Json<T>,
+    srv: web::Data<Addr<Server>>,
+) -> Result<HttpResponse, ServiceError> {
+    token::revoke(&data.t).await?;
+    srv.do_send(Revoke {
+        token: token::digest(&data.t),
+    });
+    Ok(HttpResponse::Ok().json(C { r: true }))
+}
+
 pub async fn check_auth(data: web::Json<A>) -> Result<HttpResponse, ServiceError> {
-    dbg!(&data);
     let result = get_user(&data.t)
+        .await?
         .map(|u| u.role == data.r)
         .ok_or(ServiceError::NotAuth)?;
     Ok(HttpResponse::Ok().json(C { r: result }))
 }
 
-pub fn check(message: ClientMessage) -> Result<Command, ServiceError> {
-    let user = get_user(&message.addon).ok_or(ServiceError::NotAuth)?;
+pub fn check(user: &UserData, message: ClientMessage) -> Result<Command, ServiceError> {
     user.permissions(message.command)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwarded_headers_need_a_trusted_proxy() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let forwarded = "198.51.100.1:4000";
+        assert_eq!(real_ip(Some(client), forwarded, &[proxy]), "203.0.113.7");
+        assert_eq!(real_ip(Some(proxy), forwarded, &[proxy]), "198.51.100.1");
+        assert_eq!(real_ip(Some(proxy), "", &[proxy]), "10.0.0.1");
+    }
+
+    #[test]
+    fn forwarded_entries_are_read_from_the_right() {
+        let proxies: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+        let peer = Some(proxies[0]);
+        let spoofed = "192.0.2.99, 198.51.100.1";
+        assert_eq!(real_ip(peer, spoofed, &proxies), "198.51.100.1");
+        let chained = "192.0.2.99, 198.51.100.1, 10.0.0.2";
+        assert_eq!(real_ip(peer, chained, &proxies), "198.51.100.1");
+        assert_eq!(real_ip(peer, "10.0.0.2", &proxies), "10.0.0.2");
+    }
+}
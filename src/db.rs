@@ -1,44 +1,53 @@
 use std::clone::Clone;
-use std::collections::HashMap;
-use std::iter;
+use std::collections::{HashMap, HashSet};
 use std::sync::Mutex;
 
 use actix::{fut, Actor, Addr, Context, Handler, ResponseActFuture};
+use chrono::{DateTime, Utc};
 use deadpool_postgres::{Client, Pool};
+use futures::future::join_all;
+use log::warn;
 use once_cell::sync::OnceCell;
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use rpel::get_pool;
-use rpel::user::UserList;
+use rpel::user::{User, UserList};
 
+use crate::audit::{self, AuditFilter};
 use crate::auth::check;
-use crate::dbo::{delete_item, get_item, get_list, insert_item, update_item, DBObject};
-use crate::error::ServiceError;
-use crate::server::{Msg, Server};
-use crate::users::{user_cmd, UserObject};
+use crate::dbo::{get_item, list_entity, version, DBObject};
+use crate::error::{ErrorCode, ServiceError};
+use crate::listing::{self, ListQuery};
+use crate::notify;
+use crate::password;
+use crate::registry;
+use crate::roles::{self, Action, Permission};
+use crate::scoping::{self, scoped_list, RowScope};
+use crate::search::{self, SearchQuery};
+use crate::server::{Broadcast, Change, Join, Leave, Msg, ReloadUsers, Server, Topic, Visible};
+use crate::token::{self, Token};
+use crate::trash;
+use crate::users::{user_cmd, DBUserObject, UserObject};
+use crate::validate::FieldError;
 
 #[derive(Clone)]
 pub struct UserData {
     pub id: i64,
     pub name: String,
-    pub key: String,
     pub role: i64,
+    pub permissions: HashSet<Permission>,
+    pub scope: Option<RowScope>,
 }
 
 impl UserData {
     pub fn permissions(&self, command: Command) -> Result<Command, ServiceError> {
-        if match &command {
-            Command::Get(_) => self.role >> 1 > 0,
-            Command::Insert(_) => self.role >> 2 > 0,
-            Command::Update(_) => self.role >> 3 > 0,
-            Command::Delete(_) => self.role >> 4 > 0,
-            Command::User(UserObject::Get(_)) => self.role >> 5 > 0,
-            Command::User(UserObject::GetList) => self.role >> 5 > 0,
-            Command::User(UserObject::Insert(_)) => self.role >> 6 > 0,
-            Command::User(UserObject::Update(_)) => self.role >> 7 > 0,
-            Command::User(UserObject::Delete(_)) => self.role >> 8 > 0,
-        } {
+        let command = command.canonical();
+        let (action, entity) = match command.access() {
+            Some(access) => access,
+            None => return Ok(command),
+        };
+        if roles::allows(&self.permissions, action, &entity) {
             Ok(command)
         } else {
             Err(ServiceError::NotPermission)
@@ -46,78 +55,289 @@ impl UserData {
     }
 }
 
-static USERS: OnceCell<Mutex<HashMap<String, UserData>>> = OnceCell::new();
+struct UserCache {
+    by_id: HashMap<i64, UserData>,
+    by_name: HashMap<String, i64>,
+    /// Stored keys, to tell when a user key was changed.
+    keys: HashMap<i64, String>,
+}
+
+impl UserCache {
+    fn new(
+        users: Vec<UserList>,
+        roles: &HashMap<i64, HashSet<Permission>>,
+        scopes: &HashMap<i64, RowScope>,
+    ) -> UserCache {
+        let mut by_id = HashMap::new();
+        let mut by_name = HashMap::new();
+        let mut keys = HashMap::new();
+        for user in users {
+            by_name.insert(user.name.clone(), user.id);
+            keys.insert(user.id, user.key);
+            by_id.insert(
+                user.id,
+                UserData {
+                    id: user.id,
+                    name: user.name,
+                    role: user.role,
+                    permissions: roles::resolve(roles, user.role),
+                    scope: scopes.get(&user.id).cloned(),
+                },
+            );
+        }
+        UserCache {
+            by_id,
+            by_name,
+            keys,
+        }
+    }
+
+    fn by_name(&self, name: &str) -> Option<&UserData> {
+        self.by_id.get(self.by_name.get(name)?)
+    }
+}
+
+static USERS: OnceCell<Mutex<UserCache>> = OnceCell::new();
+static POOL: OnceCell<Pool> = OnceCell::new();
+
+/// Held while the schema is set up, so instances starting together do it one
+/// after the other.
+const SCHEMA_LOCK: &str = "SELECT pg_advisory_lock(hashtext('rugo_schema'))";
+const SCHEMA_UNLOCK: &str = "SELECT pg_advisory_unlock(hashtext('rugo_schema'))";
+
+/// Creates the tables and columns the service needs. Lists, deletes and
+/// search all read the trash columns, so they are required. Without change
+/// triggers or search indexes only those features are lost, so failing to set
+/// them up is logged instead of stopping the service.
+async fn init_schema(client: &Client) -> Result<(), ServiceError> {
+    roles::init(client).await?;
+    scoping::init(client).await?;
+    audit::init(client).await?;
+    trash::init(client).await?;
+    if let Err(err) = notify::init(client).await {
+        warn!("change notification setup failed: {}", err);
+    }
+    if let Err(err) = search::init(client).await {
+        warn!("search index setup failed: {}", err);
+    }
+    Ok(())
+}
 
 pub async fn global_init() -> Result<(), ServiceError> {
-    let mut rng = thread_rng();
-    let client = get_pool().get().await?;
+    let pool = get_pool();
+    let client = pool.get().await?;
     let users = UserList::get_all(&client)
         .await
         .expect("get UserList failed");
-    let mut hash_map = HashMap::new();
-    for user in users {
-        let key = iter::repeat(())
-            .map(|()| rng.sample(Alphanumeric))
-            .take(20)
-            .collect();
-        hash_map.insert(
-            key,
-            UserData {
-                id: user.id,
-                name: user.name.clone(),
-                key: user.key.clone(),
-                role: user.role,
-            },
-        );
-    }
-    let mutex = Mutex::new(hash_map);
+    client.batch_execute(SCHEMA_LOCK).await?;
+    let schema = init_schema(&client).await;
+    client.batch_execute(SCHEMA_UNLOCK).await?;
+    schema?;
+    let roles = roles::load(&client).await?;
+    let scopes = scoping::load(&client).await?;
+    let mutex = Mutex::new(UserCache::new(users, &roles, &scopes));
     let _result = USERS.set(mutex);
+    let _result = POOL.set(pool.clone());
+    token::init(pool).await?;
     Ok(())
 }
 
+pub async fn reload_users(pool: &Pool) -> Result<Vec<String>, ServiceError> {
+    let client = pool.get().await?;
+    let roles = roles::load(&client).await?;
+    let scopes = scoping::load(&client).await?;
+    let fresh = UserCache::new(UserList::get_all(&client).await?, &roles, &scopes);
+    let stale: Vec<i64> = {
+        let mutex = USERS.get().ok_or(ServiceError::InternalServerError)?;
+        let mut users = mutex
+            .lock()
+            .map_err(|_| ServiceError::InternalServerError)?;
+        let stale = users
+            .by_id
+            .values()
+            .filter(|old| {
+                fresh.by_id.get(&old.id).map_or(true, |new| {
+                    new.role != old.role
+                        || new.permissions != old.permissions
+                        || new.scope != old.scope
+                        || fresh.keys.get(&old.id) != users.keys.get(&old.id)
+                })
+            })
+            .map(|old| old.id)
+            .collect();
+        *users = fresh;
+        stale
+    };
+    let mut keys = Vec::new();
+    for id in stale {
+        keys.extend(token::revoke_user(id).await?);
+    }
+    Ok(keys)
+}
+
 pub fn check_global() {
     let _users = USERS.get().unwrap().lock().unwrap();
 }
 
-pub fn get_user(key: &str) -> Option<UserData> {
+fn user_by_id(id: i64) -> Option<UserData> {
     let mutex = USERS.get()?;
     let users = mutex.lock().ok()?;
-    let user = users.get(key)?;
+    let user = users.by_id.get(&id)?;
     Some(user.clone())
 }
 
-pub fn get_reply(username: &str, userkey: &str) -> Option<(String, i64)> {
-    let mutex = USERS.get()?;
-    let users = mutex.lock().ok()?;
-    let reply = users
-        .iter()
-        .find(|(_key, user)| user.name == username && user.key == userkey)
-        .map(|(key, user)| (key, user.role))?;
-    Some((reply.0.clone(), reply.1))
+pub async fn get_session(key: &str) -> Result<Option<(Token, UserData)>, ServiceError> {
+    Ok(token::lookup(key)
+        .await?
+        .filter(|token| !token.is_expired())
+        .and_then(|token| user_by_id(token.user_id).map(|user| (token, user))))
+}
+
+pub async fn get_user(key: &str) -> Result<Option<UserData>, ServiceError> {
+    Ok(get_session(key).await?.map(|(_, user)| user))
+}
+
+pub async fn refresh_reply(key: &str) -> Result<Option<(String, i64)>, ServiceError> {
+    Ok(token::refresh(key)
+        .await?
+        .and_then(|token| user_by_id(token.user_id).map(|user| (token.key, user.role))))
+}
+
+pub async fn get_reply(
+    username: &str,
+    userkey: &str,
+) -> Result<Option<(String, i64)>, ServiceError> {
+    let user = {
+        let mutex = USERS.get().ok_or(ServiceError::InternalServerError)?;
+        let users = mutex
+            .lock()
+            .map_err(|_| ServiceError::InternalServerError)?;
+        users.by_name(username).map(|user| (user.id, user.role))
+    };
+    let (id, role) = match user {
+        Some(user) => user,
+        None => {
+            password::verify_dummy(userkey).await?;
+            return Ok(None);
+        }
+    };
+    let client = POOL
+        .get()
+        .ok_or(ServiceError::InternalServerError)?
+        .get()
+        .await?;
+    let mut stored = User::get(&client, id).await?;
+    if !password::verify(&stored.key, userkey).await? {
+        return Ok(None);
+    }
+    if !password::is_hashed(&stored.key) {
+        let key = password::hash(userkey).await?;
+        stored.key = key.clone();
+        User::update(&client, stored).await?;
+        // The key is the same, only its form changed.
+        let mutex = USERS.get().ok_or(ServiceError::InternalServerError)?;
+        mutex
+            .lock()
+            .map_err(|_| ServiceError::InternalServerError)?
+            .keys
+            .insert(id, key);
+    }
+    Ok(Some((token::issue(id).await?, role)))
 }
 
 #[derive(Serialize)]
 pub struct WsMsg {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
     pub command: String,
     pub name: String,
     pub object: DBObject,
+    /// Rows matching a paged list query, before the page was cut.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<usize>,
+    /// Row version to send back with the next `Update` of the item.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+    pub error: String,
+    /// Fields of an item payload that failed validation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<Vec<FieldError>>,
+}
+
+#[derive(Serialize)]
+pub struct WsErrorMsg {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    pub command: &'static str,
+    pub code: ErrorCode,
     pub error: String,
+    /// Current version and copy of an item that failed to update.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<Vec<FieldError>>,
+}
+
+impl WsErrorMsg {
+    pub fn new(id: Option<Value>, err: &ServiceError) -> WsErrorMsg {
+        WsErrorMsg {
+            id,
+            command: "Error",
+            code: err.code(),
+            error: err.to_string(),
+            current: match err {
+                ServiceError::Conflict(current) => Some(current.clone()),
+                _ => None,
+            },
+            fields: match err {
+                ServiceError::Validation(fields) => Some(fields.clone()),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn text(id: Option<Value>, err: &ServiceError) -> String {
+        serde_json::to_string(&WsErrorMsg::new(id, err)).unwrap_or_default()
+    }
+}
+
+#[derive(Serialize)]
+pub struct WsBatchMsg {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    pub command: &'static str,
+    pub object: Vec<Value>,
 }
 
 impl WsMsg {
     pub fn from_dbo(command: &str, name: String, dbo: Result<DBObject, ServiceError>) -> WsMsg {
         match dbo {
             Ok(object) => WsMsg {
+                id: None,
                 command: command.to_string(),
                 name,
                 object,
+                total: None,
+                version: None,
+                code: None,
                 error: String::new(),
+                fields: None,
             },
             Err(err) => WsMsg {
+                id: None,
                 command: command.to_string(),
                 name,
                 object: DBObject::Null,
+                total: None,
+                version: None,
+                code: Some(err.code()),
                 error: err.to_string(),
+                fields: match err {
+                    ServiceError::Validation(fields) => Some(fields),
+                    _ => None,
+                },
             },
         }
     }
@@ -143,58 +363,406 @@ impl DB {
         Ok(self.pool.get().await?)
     }
 
-    async fn get_reply(self, message: String) -> Result<String, ServiceError> {
-        let client_message: ClientMessage = serde_json::from_str(&message)?;
-        let cmd: Command = check(client_message)?;
+    /// Answers a client frame. Failures are sent back as error frames carrying
+    /// the correlation id, so the client never waits for a reply forever.
+    async fn get_reply(
+        self,
+        session: usize,
+        user: UserData,
+        message: String,
+    ) -> Result<String, ServiceError> {
+        let value: Value = match serde_json::from_str(&message) {
+            Ok(value) => value,
+            Err(err) => return Ok(WsErrorMsg::text(None, &ServiceError::from(err))),
+        };
+        let id = value.get("id").cloned();
+        match self.reply(session, user, value).await {
+            Ok(reply) => Ok(reply),
+            Err(err) => Ok(WsErrorMsg::text(id, &err)),
+        }
+    }
+
+    async fn reply(
+        &self,
+        session: usize,
+        user: UserData,
+        value: Value,
+    ) -> Result<String, ServiceError> {
+        let mut client_message: ClientMessage = serde_json::from_value(value)?;
+        let id = client_message.id.take();
+        let cmd: Command = check(&user, client_message)?;
+        match cmd {
+            Command::Batch(commands) => self.batch(session, &user, commands, id).await,
+            Command::Transaction(steps) => self.transaction(session, &user, steps, id).await,
+            cmd => self.run(session, &user, cmd, id).await,
+        }
+    }
+
+    async fn run(
+        &self,
+        session: usize,
+        user: &UserData,
+        cmd: Command,
+        id: Option<Value>,
+    ) -> Result<String, ServiceError> {
+        let client = self.client().await?;
+        let user_mutation = cmd.is_user_mutation();
+        let reply = self.execute(session, user, cmd, id, &client).await;
+        if user_mutation {
+            self.server.do_send(ReloadUsers);
+        }
+        reply
+    }
+
+    /// Answers every command of a batch in one array. Batches of reads run in
+    /// parallel on the pool, batches with writes run in order.
+    async fn batch(
+        &self,
+        session: usize,
+        user: &UserData,
+        commands: Vec<Command>,
+        id: Option<Value>,
+    ) -> Result<String, ServiceError> {
+        let results = if commands.iter().all(Command::is_read) {
+            join_all(
+                commands
+                    .into_iter()
+                    .map(|cmd| self.batch_item(session, user, cmd)),
+            )
+            .await
+        } else {
+            let mut results = Vec::with_capacity(commands.len());
+            for cmd in commands {
+                results.push(self.batch_item(session, user, cmd).await);
+            }
+            results
+        };
+        let msg = WsBatchMsg {
+            id,
+            command: "Batch",
+            object: results,
+        };
+        Ok(serde_json::to_string(&msg)?)
+    }
+
+    /// Runs the steps of a transaction on one connection and answers with the
+    /// ids they produced. Nothing is kept if a step fails.
+    async fn transaction(
+        &self,
+        session: usize,
+        user: &UserData,
+        steps: Vec<Value>,
+        id: Option<Value>,
+    ) -> Result<String, ServiceError> {
         let client = self.client().await?;
-        let msg = match cmd {
+        notify::begin(&client).await?;
+        let result = transaction_steps(user, steps, &client).await;
+        let changes = audit::finish(&client, result).await?;
+        if changes.iter().any(|change| change.entity == "User") {
+            self.server.do_send(ReloadUsers);
+        }
+        let ids = changes
+            .iter()
+            .map(|change| Value::from(change.id))
+            .collect();
+        for change in changes {
+            self.changed(session, &change.entity, &change.command, change.id);
+        }
+        let msg = WsBatchMsg {
+            id,
+            command: "Transaction",
+            object: ids,
+        };
+        Ok(serde_json::to_string(&msg)?)
+    }
+
+    async fn batch_item(&self, session: usize, user: &UserData, cmd: Command) -> Value {
+        let reply = match user.permissions(cmd) {
+            Ok(cmd) => self.run(session, user, cmd, None).await,
+            Err(err) => Err(err),
+        };
+        match reply.and_then(|text| Ok(serde_json::from_str(&text)?)) {
+            Ok(value) => value,
+            Err(err) => serde_json::to_value(WsErrorMsg::new(None, &err)).unwrap_or_default(),
+        }
+    }
+
+    /// Tells the other sessions that `entity` `id` was changed by `command`.
+    fn changed(&self, session: usize, entity: &str, command: &str, id: i64) {
+        self.server.do_send(Broadcast {
+            origin: session,
+            change: Change {
+                entity: entity.to_string(),
+                id,
+                command: command.to_string(),
+            },
+        });
+    }
+
+    async fn execute(
+        &self,
+        session: usize,
+        user: &UserData,
+        cmd: Command,
+        id: Option<Value>,
+        client: &Client,
+    ) -> Result<String, ServiceError> {
+        scoping::check(&user.scope, &cmd, client).await?;
+        let mut msg = match cmd {
             Command::Get(object) => match object {
                 Object::Item(item) => {
-                    WsMsg::from_dbo("Get", item.name.clone(), get_item(&item, &client).await)
+                    let mut msg =
+                        WsMsg::from_dbo("Get", item.name.clone(), get_item(&item, &client).await);
+                    msg.version = version(&item.name, item.id, false, &client).await?;
+                    msg
+                }
+                Object::List(query) => {
+                    let name = query.name().to_string();
+                    match (query, listing::sql_list(&name)) {
+                        (ListQuery::Page(options), Some(sql)) => {
+                            let (page, total) =
+                                listing::sql_page(sql, &options, &user.scope, &client).await?;
+                            let mut msg = WsMsg::from_dbo("Get", name, Ok(page));
+                            msg.total = Some(total);
+                            msg
+                        }
+                        (query, _) => {
+                            let list = scoped_list(&user.scope, &name, &client).await;
+                            match (query, list) {
+                                (ListQuery::Page(options), Ok(list)) => {
+                                    let (page, total) = listing::page(list, &options)?;
+                                    let mut msg = WsMsg::from_dbo("Get", name, Ok(page));
+                                    msg.total = Some(total);
+                                    msg
+                                }
+                                (_, list) => WsMsg::from_dbo("Get", name, list),
+                            }
+                        }
+                    }
                 }
-                Object::List(obj) => {
-                    WsMsg::from_dbo("Get", obj.clone(), get_list(&obj, &client).await)
+                Object::AuditList(filter) => WsMsg::from_dbo(
+                    "Get",
+                    "AuditList".to_string(),
+                    audit::get_list(&filter, &client).await,
+                ),
+                Object::ItemAt(ItemAt { name, id, at }) => {
+                    let item = Item { name, id };
+                    WsMsg::from_dbo(
+                        "Get",
+                        item.name.clone(),
+                        audit::get_item_at(&item, &at, &client).await,
+                    )
                 }
+                Object::TrashList => WsMsg::from_dbo(
+                    "Get",
+                    "TrashList".to_string(),
+                    trash::get_list(&client).await,
+                ),
             },
-            Command::Insert(dbobject) => WsMsg::from_dbo(
-                "Insert",
-                dbobject.name(),
-                Ok(insert_item(dbobject, &client)
-                    .await
-                    .map(|_| DBObject::Null)?),
-            ),
-            Command::Update(dbobject) => WsMsg::from_dbo(
-                "Update",
-                dbobject.name(),
-                Ok(update_item(dbobject, &client)
-                    .await
-                    .map(|_| DBObject::Null)?),
-            ),
-            Command::Delete(item) => WsMsg::from_dbo(
-                "Delete",
+            Command::Insert(dbobject) => {
+                let name = dbobject.name();
+                let res = audit::insert(user.id, dbobject, &client).await;
+                if let Ok(item_id) = res {
+                    self.changed(session, &name, "Insert", item_id);
+                }
+                WsMsg::from_dbo("Insert", name, res.map(|_| DBObject::Null))
+            }
+            Command::Update(Versioned {
+                object,
+                version: expected,
+            }) => {
+                let name = object.name();
+                let item_id = object.id();
+                let res = audit::update(user.id, object, expected, &client).await;
+                if let Err(err @ ServiceError::Conflict(_)) = res {
+                    return Err(err);
+                }
+                let mut msg = WsMsg::from_dbo("Update", name.clone(), res.map(|_| DBObject::Null));
+                if let (None, Some(item_id)) = (&msg.code, item_id) {
+                    self.changed(session, &name, "Update", item_id);
+                    msg.version = version(&name, item_id, false, &client).await?;
+                }
+                msg
+            }
+            Command::Delete(item) => {
+                let res = audit::delete(user.id, &item, &client).await;
+                if res.is_ok() {
+                    self.changed(session, &item.name, "Delete", item.id);
+                }
+                WsMsg::from_dbo("Delete", item.name, res.map(|_| DBObject::Null))
+            }
+            Command::History(item) => WsMsg::from_dbo(
+                "History",
                 item.name.clone(),
-                Ok(delete_item(&item, &client).await.map(|_| DBObject::Null)?),
+                audit::history(&item, &client).await,
             ),
-            Command::User(obj) => return user_cmd(obj, &client).await,
+            Command::Restore(item) => {
+                let res = audit::restore(user.id, &item, &client).await;
+                if res.is_ok() {
+                    self.changed(session, &item.name, "Restore", item.id);
+                }
+                WsMsg::from_dbo("Restore", item.name, res.map(|_| DBObject::Null))
+            }
+            Command::Purge(item) => {
+                let res = audit::purge(user.id, &item, &client).await;
+                if res.is_ok() {
+                    self.changed(session, &item.name, "Purge", item.id);
+                }
+                WsMsg::from_dbo("Purge", item.name, res.map(|_| DBObject::Null))
+            }
+            Command::User(obj) => {
+                let target = match &obj {
+                    UserObject::Update(update) => Some(update.object.id),
+                    UserObject::Delete(id) => Some(*id),
+                    _ => None,
+                };
+                let mut msg = user_cmd(user.id, obj, &client).await?;
+                if let DBUserObject::ID(item_id) = msg.object {
+                    self.changed(session, "User", &msg.command, target.unwrap_or(item_id));
+                }
+                msg.id = id;
+                return Ok(serde_json::to_string(&msg)?);
+            }
+            Command::Search(query) => {
+                let entities: Vec<&str> = search::ENTITIES
+                    .iter()
+                    .copied()
+                    .filter(|entity| roles::allows(&user.permissions, Action::Read, entity))
+                    .collect();
+                WsMsg::from_dbo(
+                    "Search",
+                    "SearchHits".to_string(),
+                    search::search(&query, &entities, &user.scope, &client).await,
+                )
+            }
+            Command::Subscribe(topic) => {
+                let name = topic.to_string();
+                self.server.do_send(Join { id: session, topic });
+                WsMsg::from_dbo("Subscribe", name, Ok(DBObject::Null))
+            }
+            Command::Unsubscribe(topic) => {
+                let name = topic.to_string();
+                self.server.do_send(Leave { id: session, topic });
+                WsMsg::from_dbo("Unsubscribe", name, Ok(DBObject::Null))
+            }
+            Command::Batch(_) | Command::Transaction(_) => {
+                return Err(ServiceError::BadRequest(
+                    "Batch and Transaction cannot be nested".to_string(),
+                ));
+            }
         };
+        msg.id = id;
         Ok(serde_json::to_string(&msg)?)
     }
 }
 
+/// Replaces `{"$ref": n}` with the id produced by step `n`.
+fn resolve_refs(value: &mut Value, ids: &[i64]) -> Result<(), ServiceError> {
+    if let Some(reference) = value.get("$ref") {
+        let id = reference
+            .as_u64()
+            .and_then(|n| ids.get(n as usize))
+            .ok_or_else(|| ServiceError::BadRequest(format!("bad reference {}", reference)))?;
+        *value = Value::from(*id);
+        return Ok(());
+    }
+    match value {
+        Value::Array(items) => items
+            .iter_mut()
+            .try_for_each(|item| resolve_refs(item, ids)),
+        Value::Object(fields) => fields
+            .values_mut()
+            .try_for_each(|item| resolve_refs(item, ids)),
+        _ => Ok(()),
+    }
+}
+
+async fn transaction_steps(
+    user: &UserData,
+    steps: Vec<Value>,
+    client: &Client,
+) -> Result<Vec<Change>, ServiceError> {
+    let mut changes: Vec<Change> = Vec::with_capacity(steps.len());
+    for mut step in steps {
+        let ids: Vec<i64> = changes.iter().map(|change| change.id).collect();
+        resolve_refs(&mut step, &ids)?;
+        let cmd = user.permissions(serde_json::from_value(step)?)?;
+        scoping::check(&user.scope, &cmd, client).await?;
+        let (command, entity, id) = match cmd {
+            Command::Insert(object) => {
+                let name = object.name();
+                (
+                    "Insert",
+                    name,
+                    audit::logged_insert(user.id, object, client).await?,
+                )
+            }
+            Command::Update(Versioned {
+                object,
+                version: expected,
+            }) => {
+                let name = object.name();
+                let id = object
+                    .id()
+                    .ok_or_else(|| ServiceError::BadRequest("bad item object".to_string()))?;
+                audit::logged_update(user.id, object, expected, client).await?;
+                ("Update", name, id)
+            }
+            Command::Delete(item) => {
+                audit::logged_delete(user.id, &item, client).await?;
+                ("Delete", item.name, item.id)
+            }
+            _ => {
+                return Err(ServiceError::BadRequest(
+                    "only Insert, Update and Delete run in a transaction".to_string(),
+                ))
+            }
+        };
+        changes.push(Change {
+            entity,
+            id,
+            command: command.to_string(),
+        });
+    }
+    Ok(changes)
+}
+
 impl Handler<Msg> for DB {
     type Result = ResponseActFuture<Self, Result<String, ServiceError>>;
 
     fn handle(&mut self, msg: Msg, _: &mut Context<Self>) -> Self::Result {
-        let message = msg.0;
         let this = self.clone();
-        Box::new(fut::wrap_future(this.get_reply(message)))
+        Box::new(fut::wrap_future(this.get_reply(
+            msg.session,
+            msg.user,
+            msg.message,
+        )))
+    }
+}
+
+impl Handler<Visible> for DB {
+    type Result = ResponseActFuture<Self, bool>;
+
+    fn handle(&mut self, msg: Visible, _: &mut Context<Self>) -> Self::Result {
+        let this = self.clone();
+        Box::new(fut::wrap_future(async move {
+            match this.client().await {
+                Ok(client) => {
+                    msg.scope
+                        .sees(&msg.change.entity, msg.change.id, &client)
+                        .await
+                }
+                Err(_) => false,
+            }
+        }))
     }
 }
 
 #[derive(Deserialize)]
 pub struct ClientMessage {
+    #[serde(default)]
+    pub id: Option<Value>,
     pub command: Command,
-    pub addon: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -203,17 +771,324 @@ pub struct Item {
     pub id: i64,
 }
 
+/// An object to update and the version it was read at.
+#[derive(Deserialize)]
+pub struct Versioned {
+    pub object: DBObject,
+    pub version: i64,
+}
+
+/// An item as it was at a point in time.
+#[derive(Debug, Deserialize)]
+pub struct ItemAt {
+    pub name: String,
+    pub id: i64,
+    pub at: DateTime<Utc>,
+}
+
 #[derive(Deserialize)]
 pub enum Object {
     Item(Item),
-    List(String),
+    ItemAt(ItemAt),
+    List(ListQuery),
+    AuditList(AuditFilter),
+    TrashList,
 }
 
 #[derive(Deserialize)]
 pub enum Command {
     Get(Object),
     Insert(DBObject),
-    Update(DBObject),
+    Update(Versioned),
     Delete(Item),
+    History(Item),
+    Restore(Item),
+    Purge(Item),
     User(UserObject),
+    Subscribe(Topic),
+    Unsubscribe(Topic),
+    Search(SearchQuery),
+    Batch(Vec<Command>),
+    Transaction(Vec<Value>),
+}
+
+/// Replaces an entity alias such as `Siren_type` with the entity name.
+fn canonical(name: &mut String) {
+    if let Some(entity) = registry::entity(name) {
+        if entity.name != name {
+            *name = entity.name.to_string();
+        }
+    }
+}
+
+impl Command {
+    /// Names every entity by its registry name, so permissions, the audit log
+    /// and change notices never see an alias.
+    fn canonical(mut self) -> Command {
+        match &mut self {
+            Command::Get(Object::Item(Item { name, .. }))
+            | Command::Get(Object::ItemAt(ItemAt { name, .. }))
+            | Command::Delete(Item { name, .. })
+            | Command::History(Item { name, .. })
+            | Command::Restore(Item { name, .. })
+            | Command::Purge(Item { name, .. })
+            | Command::Subscribe(Topic { entity: name, .. })
+            | Command::Unsubscribe(Topic { entity: name, .. }) => canonical(name),
+            _ => (),
+        }
+        self
+    }
+
+    /// Action and target entity name checked against the user permissions.
+    /// The commands of a batch and the steps of a transaction are checked one
+    /// by one.
+    fn access(&self) -> Option<(Action, String)> {
+        let access = match self {
+            Command::Get(Object::Item(item)) => (Action::Read, item.name.clone()),
+            Command::Get(Object::ItemAt(item)) => (Action::Read, item.name.clone()),
+            Command::Get(Object::List(query)) => {
+                (Action::Read, list_entity(query.name()).to_string())
+            }
+            Command::Get(Object::AuditList(_)) => (Action::Read, "Audit".to_string()),
+            Command::Get(Object::TrashList) => (Action::Read, "Trash".to_string()),
+            Command::Insert(object) => (Action::Insert, object.name()),
+            Command::Update(update) => (Action::Update, update.object.name()),
+            Command::Delete(item) => (Action::Delete, item.name.clone()),
+            Command::History(item) => (Action::Read, item.name.clone()),
+            Command::Restore(_) => (Action::Update, "Trash".to_string()),
+            Command::Purge(_) => (Action::Delete, "Trash".to_string()),
+            Command::User(UserObject::Get(_)) => (Action::Read, "User".to_string()),
+            Command::User(UserObject::GetList) => (Action::Read, "User".to_string()),
+            Command::User(UserObject::Insert(_)) => (Action::Insert, "User".to_string()),
+            Command::User(UserObject::Update(_)) => (Action::Update, "User".to_string()),
+            Command::User(UserObject::Delete(_)) => (Action::Delete, "User".to_string()),
+            Command::Subscribe(topic) | Command::Unsubscribe(topic) => {
+                (Action::Read, topic.entity.clone())
+            }
+            Command::Search(_) => (Action::Read, "Search".to_string()),
+            Command::Batch(_) | Command::Transaction(_) => return None,
+        };
+        Some(access)
+    }
+
+    fn is_read(&self) -> bool {
+        matches!(self, Command::Get(_) | Command::Search(_))
+    }
+
+    fn is_user_mutation(&self) -> bool {
+        match self {
+            Command::Update(update) => update.object.name() == "User",
+            Command::Delete(item) => item.name == "User",
+            Command::Insert(object) => object.name() == "User",
+            Command::User(object) => !matches!(object, UserObject::Get(_) | UserObject::GetList),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::users::VersionedUser;
+    use crate::validate;
+
+    fn user(permissions: &[&str]) -> UserData {
+        UserData {
+            id: 1,
+            name: "test".to_string(),
+            role: 1,
+            permissions: permissions.iter().map(|p| p.parse().unwrap()).collect(),
+            scope: None,
+        }
+    }
+
+    fn item(name: &str) -> Item {
+        Item {
+            name: name.to_string(),
+            id: 1,
+        }
+    }
+
+    fn topic(name: &str) -> Topic {
+        Topic::try_from(name.to_string()).unwrap()
+    }
+
+    fn commands() -> Vec<(Command, &'static str)> {
+        vec![
+            (
+                Command::Get(Object::List(ListQuery::Name("ContactList".to_string()))),
+                "read",
+            ),
+            (Command::Get(Object::Item(item("Contact"))), "read"),
+            (
+                Command::Get(Object::ItemAt(ItemAt {
+                    name: "Contact".to_string(),
+                    id: 1,
+                    at: Utc::now(),
+                })),
+                "read",
+            ),
+            (
+                Command::Get(Object::AuditList(AuditFilter::default())),
+                "read:Audit",
+            ),
+            (Command::Insert(DBObject::Null), "insert"),
+            (
+                Command::Update(Versioned {
+                    object: DBObject::Null,
+                    version: 0,
+                }),
+                "update",
+            ),
+            (Command::Delete(item("Contact")), "delete"),
+            (Command::History(item("Contact")), "read"),
+            (Command::User(UserObject::Get(1)), "read:User"),
+            (Command::User(UserObject::GetList), "read:User"),
+            (
+                Command::User(UserObject::Insert(User::default())),
+                "insert:User",
+            ),
+            (
+                Command::User(UserObject::Update(VersionedUser {
+                    object: User::default(),
+                    version: 0,
+                })),
+                "update:User",
+            ),
+            (Command::User(UserObject::Delete(1)), "delete:User"),
+            (Command::Subscribe(topic("ContactList")), "read"),
+            (
+                Command::Search(SearchQuery {
+                    text: "test".to_string(),
+                    limit: None,
+                }),
+                "read",
+            ),
+            (Command::Unsubscribe(topic("Contact:42")), "read"),
+        ]
+    }
+
+    const ALL: [&str; 8] = [
+        "read",
+        "insert",
+        "update",
+        "delete",
+        "read:User",
+        "insert:User",
+        "update:User",
+        "delete:User",
+    ];
+
+    #[test]
+    fn granted_permission_allows_command() {
+        for (command, permission) in commands() {
+            assert!(user(&[permission]).permissions(command).is_ok());
+        }
+    }
+
+    #[test]
+    fn other_permissions_deny_command() {
+        for (command, permission) in commands() {
+            let others: Vec<&str> = ALL
+                .iter()
+                .copied()
+                .filter(|other| *other != permission)
+                .collect();
+            assert!(user(&others).permissions(command).is_err());
+        }
+    }
+
+    #[test]
+    fn entity_grant_is_limited_to_entity() {
+        let technician = user(&["read:SirenType", "update:Siren"]);
+        assert!(technician
+            .permissions(Command::Get(Object::List(ListQuery::Name(
+                "SirenTypeSelect".to_string()
+            ))))
+            .is_ok());
+        assert!(technician
+            .permissions(Command::Delete(item("Siren")))
+            .is_err());
+        assert!(technician
+            .permissions(Command::Get(Object::Item(item("Contact"))))
+            .is_err());
+    }
+
+    #[test]
+    fn wildcard_grant_excludes_users() {
+        let operator = user(&["read", "delete"]);
+        assert!(operator
+            .permissions(Command::Get(Object::Item(item("User"))))
+            .is_err());
+        assert!(operator.permissions(Command::Delete(item("User"))).is_err());
+        assert!(operator.permissions(Command::Delete(item("Rank"))).is_ok());
+    }
+
+    #[test]
+    fn aliases_are_checked_as_entities() {
+        let technician = user(&["delete:SirenType", "read:SirenType"]);
+        match technician.permissions(Command::Delete(item("Siren_type"))) {
+            Ok(Command::Delete(item)) => assert_eq!(item.name, "SirenType"),
+            _ => panic!("alias delete refused"),
+        }
+        match technician.permissions(Command::Subscribe(topic("Siren_type:1"))) {
+            Ok(Command::Subscribe(topic)) => assert_eq!(topic.entity, "SirenType"),
+            _ => panic!("alias subscribe refused"),
+        }
+        assert!(user(&["delete:Siren_type"])
+            .permissions(Command::Delete(item("Siren_type")))
+            .is_err());
+    }
+
+    #[test]
+    fn trash_needs_explicit_grants() {
+        let operator = user(&["read", "update", "delete"]);
+        assert!(operator
+            .permissions(Command::Get(Object::TrashList))
+            .is_err());
+        assert!(operator
+            .permissions(Command::Restore(item("Company")))
+            .is_err());
+        let admin = user(&["read:Trash", "update:Trash", "delete:Trash"]);
+        assert!(admin.permissions(Command::Get(Object::TrashList)).is_ok());
+        assert!(admin.permissions(Command::Restore(item("Company"))).is_ok());
+        assert!(admin.permissions(Command::Purge(item("Company"))).is_ok());
+    }
+
+    #[test]
+    fn batch_commands_are_checked_one_by_one() {
+        let reader = user(&["read"]);
+        assert!(reader
+            .permissions(Command::Batch(vec![Command::Delete(item("Contact"))]))
+            .is_ok());
+        assert!(reader
+            .permissions(Command::Delete(item("Contact")))
+            .is_err());
+    }
+
+    #[test]
+    fn transaction_refs_resolve_to_step_ids() {
+        let mut step = serde_json::json!({
+            "Insert": {"Contact": {"company_id": {"$ref": 1}, "emails": [{"$ref": 0}]}}
+        });
+        resolve_refs(&mut step, &[7, 42]).unwrap();
+        assert_eq!(step["Insert"]["Contact"]["company_id"], 42);
+        assert_eq!(step["Insert"]["Contact"]["emails"][0], 7);
+        assert!(resolve_refs(&mut serde_json::json!({"$ref": 2}), &[7, 42]).is_err());
+    }
+
+    #[test]
+    fn insert_reply_lists_invalid_fields() {
+        let res = validate::check(&DBObject::User(User::default()));
+        let msg = WsMsg::from_dbo("Insert", "User".to_string(), res.map(|_| DBObject::Null));
+        let reply = serde_json::to_value(&msg).unwrap();
+        assert_eq!(
+            reply["code"],
+            serde_json::to_value(ErrorCode::Validation).unwrap()
+        );
+        assert_eq!(reply["fields"][0]["field"], "name");
+        assert_eq!(reply["fields"][0]["error"], "required");
+    }
 }
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::fmt;
 
 use deadpool_postgres::Client;
@@ -18,12 +19,37 @@ use rpel::siren::{Siren, SirenList};
 use rpel::siren_type::{SirenType, SirenTypeList};
 use rpel::user::{User, UserList};
 
+use crate::audit::{AuditEntry, HistoryEntry};
 use crate::db::{Item, Object};
 use crate::error::ServiceError;
+use crate::registry::{self, Entity};
+use crate::search::SearchHit;
+use crate::trash::{self, TrashEntry};
+use crate::validate;
 
-#[derive(Deserialize, Serialize)]
-pub enum DBObject {
-    Null,
+/// Declares `DBObject` with `name()` giving each variant its own name, so the
+/// names in replies, in the registry and in the serde tags cannot drift apart.
+macro_rules! db_objects {
+    ($($variant:ident($payload:ty),)*) => {
+        #[derive(Deserialize, Serialize)]
+        pub enum DBObject {
+            Null,
+            $($variant($payload),)*
+        }
+
+        impl DBObject {
+            pub fn name(&self) -> String {
+                match self {
+                    DBObject::Null => String::new(),
+                    $(DBObject::$variant(_) => String::from(stringify!($variant)),)*
+                }
+            }
+        }
+    };
+}
+
+db_objects! {
+    AuditList(Vec<AuditEntry>),
     Certificate(Certificate),
     CertificateList(Vec<CertificateList>),
     Company(Box<Company>),
@@ -35,6 +61,7 @@ pub enum DBObject {
     Education(Education),
     EducationList(Vec<EducationList>),
     EducationShort(Vec<EducationShort>),
+    History(Vec<HistoryEntry>),
     Kind(Kind),
     KindList(Vec<KindList>),
     Post(Post),
@@ -46,206 +73,166 @@ pub enum DBObject {
     RankList(Vec<RankList>),
     Scope(Scope),
     ScopeList(Vec<ScopeList>),
+    SearchHits(Vec<SearchHit>),
     SelectItem(Vec<SelectItem>),
     Siren(Box<Siren>),
     SirenList(Vec<SirenList>),
     SirenType(SirenType),
     SirenTypeList(Vec<SirenTypeList>),
+    TrashList(Vec<TrashEntry>),
     User(User),
     UserList(Vec<UserList>),
 }
 
 impl DBObject {
-    pub fn name(&self) -> String {
+    pub fn id(&self) -> Option<i64> {
+        match self {
+            DBObject::Certificate(item) => Some(item.id),
+            DBObject::Company(item) => Some(item.id),
+            DBObject::Contact(item) => Some(item.id),
+            DBObject::Department(item) => Some(item.id),
+            DBObject::Education(item) => Some(item.id),
+            DBObject::Kind(item) => Some(item.id),
+            DBObject::Post(item) => Some(item.id),
+            DBObject::Practice(item) => Some(item.id),
+            DBObject::Rank(item) => Some(item.id),
+            DBObject::Scope(item) => Some(item.id),
+            DBObject::Siren(item) => Some(item.id),
+            DBObject::SirenType(item) => Some(item.id),
+            DBObject::User(item) => Some(item.id),
+            _ => None,
+        }
+    }
+
+    /// Keeps only the rows with the given ids in lists of scoped entities.
+    pub fn retain_ids(self, ids: &HashSet<i64>) -> DBObject {
         match self {
-            DBObject::Null => String::new(),
-            DBObject::Certificate(_) => String::from("Certificate"),
-            DBObject::CertificateList(_) => String::from("CertificateList"),
-            DBObject::Company(_) => String::from("Company"),
-            DBObject::CompanyList(_) => String::from("CompanyList"),
-            DBObject::Contact(_) => String::from("Contact"),
-            DBObject::ContactList(_) => String::from("ContactList"),
-            DBObject::Department(_) => String::from("Department"),
-            DBObject::DepartmentList(_) => String::from("DepartmentList"),
-            DBObject::Education(_) => String::from("Education"),
-            DBObject::EducationList(_) => String::from("EducationList"),
-            DBObject::EducationShort(_) => String::from("EducationShort"),
-            DBObject::Kind(_) => String::from("Kind"),
-            DBObject::KindList(_) => String::from("KindList"),
-            DBObject::Post(_) => String::from("Post"),
-            DBObject::PostList(_) => String::from("PostList"),
-            DBObject::Practice(_) => String::from("Practice"),
-            DBObject::PracticeList(_) => String::from("PracticeList"),
-            DBObject::PracticeShort(_) => String::from("PracticeShort"),
-            DBObject::Rank(_) => String::from("Rank"),
-            DBObject::RankList(_) => String::from("RankList"),
-            DBObject::Scope(_) => String::from("Scope"),
-            DBObject::ScopeList(_) => String::from("ScopeList"),
-            DBObject::SelectItem(_) => String::from("SelectItem"),
-            DBObject::Siren(_) => String::from("Siren"),
-            DBObject::SirenList(_) => String::from("SirenList"),
-            DBObject::SirenType(_) => String::from("SirenType"),
-            DBObject::SirenTypeList(_) => String::from("SirenTypeList"),
-            DBObject::User(_) => String::from("User"),
-            DBObject::UserList(_) => String::from("UserList"),
+            DBObject::CompanyList(mut list) => {
+                list.retain(|item| ids.contains(&item.id));
+                DBObject::CompanyList(list)
+            }
+            DBObject::ContactList(mut list) => {
+                list.retain(|item| ids.contains(&item.id));
+                DBObject::ContactList(list)
+            }
+            DBObject::PracticeList(mut list) => {
+                list.retain(|item| ids.contains(&item.id));
+                DBObject::PracticeList(list)
+            }
+            DBObject::PracticeShort(mut list) => {
+                list.retain(|item| ids.contains(&item.id));
+                DBObject::PracticeShort(list)
+            }
+            DBObject::SelectItem(mut list) => {
+                list.retain(|item| ids.contains(&item.id));
+                DBObject::SelectItem(list)
+            }
+            DBObject::SirenList(mut list) => {
+                list.retain(|item| ids.contains(&item.id));
+                DBObject::SirenList(list)
+            }
+            object => object,
         }
     }
 }
 
+pub fn table(entity: &str) -> Option<&'static str> {
+    registry::entity(entity).map(|entity| entity.table)
+}
+
+/// Row version of an item, taken from the Postgres `xmin` system column. It
+/// changes with every update of the row. `lock` holds the row until the end of
+/// the transaction.
+pub async fn version(
+    entity: &str,
+    id: i64,
+    lock: bool,
+    client: &Client,
+) -> Result<Option<i64>, ServiceError> {
+    let table = match table(entity) {
+        Some(table) => table,
+        None => return Ok(None),
+    };
+    let row = client
+        .query_opt(
+            format!(
+                "SELECT xmin::text::bigint FROM {} WHERE id = $1{}",
+                table,
+                if lock { " FOR UPDATE" } else { "" }
+            )
+            .as_str(),
+            &[&id],
+        )
+        .await?;
+    Ok(row.map(|row| row.get(0)))
+}
+
+/// Entity behind a list or select object name, used for access checks.
+/// Other names, such as topics of a single entity, are returned as they are.
+pub fn list_entity(name: &str) -> &str {
+    registry::list(name).map_or(name, |list| list.entity)
+}
+
 impl fmt::Display for Object {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Object::Item(i) => write!(f, "Item {} {}", i.id, i.name),
             Object::List(s) => write!(f, "List {}", s),
+            Object::AuditList(filter) => write!(f, "AuditList {:?}", filter),
+            Object::ItemAt(i) => write!(f, "Item {} {} at {}", i.id, i.name, i.at),
+            Object::TrashList => write!(f, "TrashList"),
         }
     }
 }
 
+fn bad_item(item: &Item) -> ServiceError {
+    ServiceError::BadRequest(format!("bad item object: {} {}", item.name, item.id))
+}
+
+fn object_entity(object: &DBObject) -> Result<&'static Entity, ServiceError> {
+    registry::entity(&object.name())
+        .ok_or_else(|| ServiceError::BadRequest("bad item object".to_string()))
+}
+
 pub async fn get_item(item: &Item, client: &Client) -> Result<DBObject, ServiceError> {
-    match (item.name.as_str(), item.id) {
-        ("Certificate", id) => Ok(DBObject::Certificate(Certificate::get(&client, id).await?)),
-        ("Company", id) => Ok(DBObject::Company(Box::new(
-            Company::get(&client, id).await?,
-        ))),
-        ("Contact", id) => Ok(DBObject::Contact(Box::new(
-            Contact::get(&client, id).await?,
-        ))),
-        ("Department", id) => Ok(DBObject::Department(Department::get(&client, id).await?)),
-        ("Education", id) => Ok(DBObject::Education(Education::get(&client, id).await?)),
-        ("Kind", id) => Ok(DBObject::Kind(Kind::get(&client, id).await?)),
-        ("Post", id) => Ok(DBObject::Post(Post::get(&client, id).await?)),
-        ("Practice", id) => Ok(DBObject::Practice(Practice::get(&client, id).await?)),
-        ("Rank", id) => Ok(DBObject::Rank(Rank::get(&client, id).await?)),
-        ("Scope", id) => Ok(DBObject::Scope(Scope::get(&client, id).await?)),
-        ("Siren", id) => Ok(DBObject::Siren(Box::new(Siren::get(&client, id).await?))),
-        ("SirenType", id) => Ok(DBObject::SirenType(SirenType::get(&client, id).await?)),
-        ("User", id) => Ok(DBObject::User(User::get(&client, id).await?)),
-        (e, id) => Err(ServiceError::BadRequest(format!(
-            "bad item object: {} {}",
-            e, id
-        ))),
-    }
+    let entity = registry::entity(&item.name).ok_or_else(|| bad_item(item))?;
+    (entity.get)(client, item.id).await
 }
 
+/// Loads a list or select object, leaving out trashed rows.
 pub async fn get_list(name: &str, client: &Client) -> Result<DBObject, ServiceError> {
-    match name {
-        "CertificateList" => Ok(DBObject::CertificateList(
-            CertificateList::get_all(&client).await?,
-        )),
-        "CompanyList" => Ok(DBObject::CompanyList(CompanyList::get_all(&client).await?)),
-        "CompanySelect" => Ok(DBObject::SelectItem(
-            SelectItem::company_all(&client).await?,
-        )),
-        "ContactList" => Ok(DBObject::ContactList(ContactList::get_all(&client).await?)),
-        "ContactSelect" => Ok(DBObject::SelectItem(
-            SelectItem::contact_all(&client).await?,
-        )),
-        "DepartmentList" => Ok(DBObject::DepartmentList(
-            DepartmentList::get_all(&client).await?,
-        )),
-        "DepartmentSelect" => Ok(DBObject::SelectItem(
-            SelectItem::department_all(&client).await?,
-        )),
-        "EducationList" => Ok(DBObject::EducationList(
-            EducationList::get_all(&client).await?,
-        )),
-        "EducationNear" => Ok(DBObject::EducationShort(
-            EducationShort::get_near(&client).await?,
-        )),
-        // "EducationShort" =>
-        "KindList" => Ok(DBObject::KindList(KindList::get_all(&client).await?)),
-        "KindSelect" => Ok(DBObject::SelectItem(SelectItem::kind_all(&client).await?)),
-        "PostList" => Ok(DBObject::PostList(PostList::get_all(&client).await?)),
-        "PostSelect" => Ok(DBObject::SelectItem(
-            SelectItem::post_all(&client, false).await?,
-        )),
-        "PostGoSelect" => Ok(DBObject::SelectItem(
-            SelectItem::post_all(&client, true).await?,
-        )),
-        "PracticeList" => Ok(DBObject::PracticeList(
-            PracticeList::get_all(&client).await?,
-        )),
-        "PracticeNear" => Ok(DBObject::PracticeShort(
-            PracticeShort::get_near(&client).await?,
-        )),
-        // "PracticeShort" =>
-        "RankList" => Ok(DBObject::RankList(RankList::get_all(&client).await?)),
-        "RankSelect" => Ok(DBObject::SelectItem(SelectItem::rank_all(&client).await?)),
-        "ScopeList" => Ok(DBObject::ScopeList(ScopeList::get_all(&client).await?)),
-        "ScopeSelect" => Ok(DBObject::SelectItem(SelectItem::scope_all(&client).await?)),
-        // "SelectItem" =>
-        "SirenList" => Ok(DBObject::SirenList(SirenList::get_all(&client).await?)),
-        "SirenTypeList" => Ok(DBObject::SirenTypeList(
-            SirenTypeList::get_all(&client).await?,
-        )),
-        "SirenTypeSelect" => Ok(DBObject::SelectItem(
-            SelectItem::siren_type_all(&client).await?,
-        )),
-        "UserList" => Ok(DBObject::UserList(UserList::get_all(&client).await?)),
-        e => Err(ServiceError::BadRequest(format!("bad list object: {}", e))),
-    }
+    let list = registry::list(name)
+        .ok_or_else(|| ServiceError::BadRequest(format!("bad list object: {}", name)))?;
+    let object = (list.load)(client).await?;
+    trash::hide_deleted(name, object, client).await
 }
 
 pub async fn insert_item(object: DBObject, client: &Client) -> Result<i64, ServiceError> {
-    match object {
-        DBObject::Certificate(item) => Ok(Certificate::insert(&client, item).await?.id),
-        DBObject::Company(item) => Ok(Company::insert(&client, *item).await?.id),
-        DBObject::Contact(item) => Ok(Contact::insert(&client, *item).await?.id),
-        DBObject::Department(item) => Ok(Department::insert(&client, item).await?.id),
-        DBObject::Education(item) => Ok(Education::insert(&client, item).await?.id),
-        DBObject::Kind(item) => Ok(Kind::insert(&client, item).await?.id),
-        DBObject::Post(item) => Ok(Post::insert(&client, item).await?.id),
-        DBObject::Practice(item) => Ok(Practice::insert(&client, item).await?.id),
-        DBObject::Rank(item) => Ok(Rank::insert(&client, item).await?.id),
-        DBObject::Scope(item) => Ok(Scope::insert(&client, item).await?.id),
-        DBObject::Siren(item) => Ok(Siren::insert(&client, *item).await?.id),
-        DBObject::SirenType(item) => Ok(SirenType::insert(&client, item).await?.id),
-        DBObject::User(item) => Ok(User::insert(&client, item).await?.id),
-        _ => Err(ServiceError::BadRequest("bad item object".to_string())),
-    }
+    validate::check(&object)?;
+    let entity = object_entity(&object)?;
+    (entity.insert)(client, object).await
 }
 
 pub async fn update_item(object: DBObject, client: &Client) -> Result<i64, ServiceError> {
-    let res = match object {
-        DBObject::Certificate(item) => Certificate::update(&client, item).await,
-        DBObject::Company(item) => Company::update(&client, *item).await,
-        DBObject::Contact(item) => Contact::update(&client, *item).await,
-        DBObject::Department(item) => Department::update(&client, item).await,
-        DBObject::Education(item) => Education::update(&client, item).await,
-        DBObject::Kind(item) => Kind::update(&client, item).await,
-        DBObject::Post(item) => Post::update(&client, item).await,
-        DBObject::Practice(item) => Practice::update(&client, item).await,
-        DBObject::Rank(item) => Rank::update(&client, item).await,
-        DBObject::Scope(item) => Scope::update(&client, item).await,
-        DBObject::Siren(item) => Siren::update(&client, *item).await,
-        DBObject::SirenType(item) => SirenType::update(&client, item).await,
-        DBObject::User(item) => User::update(&client, item).await,
-        _ => return Err(ServiceError::BadRequest("bad item object".to_string())),
-    }?;
-    Ok(res as i64)
+    validate::check(&object)?;
+    let entity = object_entity(&object)?;
+    (entity.update)(client, object).await
 }
 
 pub async fn delete_item(item: &Item, client: &Client) -> Result<i64, ServiceError> {
-    let res = match item.name.as_str() {
-        "Certificate" => Certificate::delete(client, item.id).await,
-        "Company" => Company::delete(client, item.id).await,
-        "Contact" => Contact::delete(client, item.id).await,
-        "Department" => Department::delete(client, item.id).await,
-        "Education" => Education::delete(client, item.id).await,
-        "Kind" => Kind::delete(client, item.id).await,
-        "Post" => Post::delete(client, item.id).await,
-        "Practice" => Practice::delete(client, item.id).await,
-        "Rank" => Rank::delete(client, item.id).await,
-        "Scope" => Scope::delete(client, item.id).await,
-        "Siren" => Siren::delete(client, item.id).await,
-        "Siren_type" => SirenType::delete(client, item.id).await,
-        "User" => User::delete(client, item.id).await,
-        _ => {
-            return Err(ServiceError::BadRequest(format!(
-                "bad path {:?}",
-                item.name
-            )))
-        }
-    }?;
-    Ok(res as i64)
+    let entity = registry::entity(&item.name).ok_or_else(|| bad_item(item))?;
+    (entity.delete)(client, item.id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_names_map_to_entities() {
+        assert_eq!(list_entity("ContactList"), "Contact");
+        assert_eq!(list_entity("CompanySelect"), "Company");
+        assert_eq!(list_entity("PostGoSelect"), "Post");
+        assert_eq!(list_entity("PracticeNear"), "Practice");
+    }
 }
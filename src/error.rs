@@ -1,13 +1,21 @@
-use actix_web::{error::ResponseError, HttpResponse};
+use std::error::Error as StdError;
+
+use actix_web::{error::ResponseError, http::StatusCode, HttpResponse};
 use deadpool_postgres::PoolError;
 use rpel::error::RpelError;
+use serde::Serialize;
 use serde_json::error::Error as SJError;
+use serde_json::Value;
 use thiserror::Error;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::Error as PGError;
+
+use crate::validate::FieldError;
 
 #[derive(Debug, Error)]
 pub enum ServiceError {
-    // #[error("Internal Server Error")]
-    // InternalServerError,
+    #[error("Internal Server Error")]
+    InternalServerError,
     #[error("Bad request: {0}")]
     BadRequest(String),
 
@@ -19,22 +27,146 @@ pub enum ServiceError {
     #[error("DB Error: {0}")]
     DBError(RpelError),
 
+    #[error("PG Error: {0}")]
+    PGError(PGError),
+
     #[error("Serde JSON error: {0}")]
     SJError(SJError),
     #[error("Not auth")]
     NotAuth,
+    #[error("Wrong username or password")]
+    FailedAuth,
     #[error("Not permission")]
     NotPermission,
+    #[error("Too many requests, retry in {0} s")]
+    TooManyRequests(u64),
+    /// The row changed since it was read; holds the current version and copy.
+    #[error("Item was changed by someone else")]
+    Conflict(Value),
+    #[error("Invalid fields")]
+    Validation(Vec<FieldError>),
     // #[error("Error get client")]
     // ClientGet,
 }
 
+/// Stable error codes shared by HTTP error bodies and WebSocket error frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum ErrorCode {
+    #[serde(rename = "internal")]
+    Internal,
+    #[serde(rename = "request.bad")]
+    BadRequest,
+    #[serde(rename = "request.bad_json")]
+    BadJson,
+    #[serde(rename = "auth.invalid_token")]
+    InvalidToken,
+    #[serde(rename = "auth.failed")]
+    FailedAuth,
+    #[serde(rename = "auth.rate_limited")]
+    RateLimited,
+    #[serde(rename = "perm.denied")]
+    PermissionDenied,
+    #[serde(rename = "db.unavailable")]
+    DbUnavailable,
+    #[serde(rename = "db.error")]
+    Db,
+    #[serde(rename = "db.not_found")]
+    NotFound,
+    #[serde(rename = "db.unique_violation")]
+    UniqueViolation,
+    #[serde(rename = "db.foreign_key_violation")]
+    ForeignKeyViolation,
+    #[serde(rename = "db.not_null_violation")]
+    NotNullViolation,
+    #[serde(rename = "db.check_violation")]
+    CheckViolation,
+    #[serde(rename = "db.conflict")]
+    Conflict,
+    #[serde(rename = "validation.field")]
+    Validation,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::Internal | ErrorCode::Db => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::BadRequest | ErrorCode::BadJson => StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidToken | ErrorCode::FailedAuth => StatusCode::UNAUTHORIZED,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::PermissionDenied => StatusCode::FORBIDDEN,
+            ErrorCode::DbUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::UniqueViolation | ErrorCode::ForeignKeyViolation | ErrorCode::Conflict => {
+                StatusCode::CONFLICT
+            }
+            ErrorCode::NotNullViolation | ErrorCode::CheckViolation | ErrorCode::Validation => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+        }
+    }
+}
+
+/// Looks through the error chain for a Postgres error and maps its SQLSTATE.
+fn db_code(error: &(dyn StdError + 'static)) -> ErrorCode {
+    let mut source = Some(error);
+    while let Some(err) = source {
+        if let Some(pg) = err.downcast_ref::<PGError>() {
+            return match pg.code() {
+                Some(code) if *code == SqlState::UNIQUE_VIOLATION => ErrorCode::UniqueViolation,
+                Some(code) if *code == SqlState::FOREIGN_KEY_VIOLATION => {
+                    ErrorCode::ForeignKeyViolation
+                }
+                Some(code) if *code == SqlState::NOT_NULL_VIOLATION => ErrorCode::NotNullViolation,
+                Some(code) if *code == SqlState::CHECK_VIOLATION => ErrorCode::CheckViolation,
+                // query_one on a missing row; tokio-postgres 0.5 has no kind
+                // for it, only the text of `Error::row_count`: "query
+                // returned an unexpected number of rows".
+                None if pg.to_string().contains("unexpected number of rows") => ErrorCode::NotFound,
+                _ => ErrorCode::Db,
+            };
+        }
+        source = err.source();
+    }
+    ErrorCode::Db
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: ErrorCode,
+    error: String,
+}
+
+impl ServiceError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ServiceError::InternalServerError => ErrorCode::Internal,
+            ServiceError::BadRequest(_) => ErrorCode::BadRequest,
+            ServiceError::PoolError(_) => ErrorCode::DbUnavailable,
+            ServiceError::DBError(err) => db_code(err),
+            ServiceError::PGError(err) => db_code(err),
+            ServiceError::SJError(_) => ErrorCode::BadJson,
+            ServiceError::NotAuth => ErrorCode::InvalidToken,
+            ServiceError::FailedAuth => ErrorCode::FailedAuth,
+            ServiceError::NotPermission => ErrorCode::PermissionDenied,
+            ServiceError::TooManyRequests(_) => ErrorCode::RateLimited,
+            ServiceError::Conflict(_) => ErrorCode::Conflict,
+            ServiceError::Validation(_) => ErrorCode::Validation,
+        }
+    }
+}
+
 impl From<RpelError> for ServiceError {
     fn from(error: RpelError) -> Self {
         Self::DBError(error)
     }
 }
 
+impl From<PGError> for ServiceError {
+    fn from(error: PGError) -> Self {
+        Self::PGError(error)
+    }
+}
+
 impl From<PoolError> for ServiceError {
     fn from(error: PoolError) -> Self {
         Self::PoolError(error)
@@ -42,33 +174,20 @@ impl From<PoolError> for ServiceError {
 }
 
 impl ResponseError for ServiceError {
+    fn status_code(&self) -> StatusCode {
+        self.code().status()
+    }
+
     fn error_response(&self) -> HttpResponse {
-        match self {
-            // ServiceError::InternalServerError => HttpResponse::BadRequest()
-            //     .reason("Internal server error. Please try again later")
-            //     .finish(),
-            ServiceError::BadRequest(_) => {
-                HttpResponse::BadRequest().reason("bad request").finish()
-            }
-            // ServiceError::IOError(_) => HttpResponse::BadRequest().reason("io error").finish(),
-            ServiceError::PoolError(_) => HttpResponse::BadRequest()
-                .reason("unable to connect to the database")
-                .finish(),
-            ServiceError::DBError(_) => HttpResponse::BadRequest().reason("db error").finish(),
-            ServiceError::SJError(_) => HttpResponse::BadRequest()
-                .reason("serde json error")
-                .finish(),
-            ServiceError::NotAuth => HttpResponse::NotFound().finish(),
-            // ServiceError::FailedAuth => HttpResponse::BadRequest()
-            //     .reason("Internal server error. Please try again later")
-            //     .finish(),
-            // ServiceError::ClientGet => HttpResponse::BadRequest()
-            //     .reason("Internal server error. Please try again later")
-            //     .finish(),
-            ServiceError::NotPermission => HttpResponse::BadRequest()
-                .reason("Internal server error. Please try again later")
-                .finish(),
+        let code = self.code();
+        let mut response = HttpResponse::build(code.status());
+        if let ServiceError::TooManyRequests(secs) = self {
+            response.header("Retry-After", secs.to_string());
         }
+        response.json(ErrorBody {
+            code,
+            error: self.to_string(),
+        })
     }
 }
 
@@ -77,3 +196,94 @@ impl From<SJError> for ServiceError {
         Self::SJError(error)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn errors_map_to_stable_codes() {
+        let bad_json = serde_json::from_str::<Value>("{").unwrap_err();
+        let cases = vec![
+            (
+                ServiceError::InternalServerError,
+                "internal",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                ServiceError::BadRequest("bad".to_string()),
+                "request.bad",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                ServiceError::SJError(bad_json),
+                "request.bad_json",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                ServiceError::NotAuth,
+                "auth.invalid_token",
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                ServiceError::FailedAuth,
+                "auth.failed",
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                ServiceError::NotPermission,
+                "perm.denied",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                ServiceError::TooManyRequests(3),
+                "auth.rate_limited",
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            (
+                ServiceError::Conflict(Value::Null),
+                "db.conflict",
+                StatusCode::CONFLICT,
+            ),
+            (
+                ServiceError::Validation(Vec::new()),
+                "validation.field",
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+        ];
+        for (error, code, status) in cases {
+            assert_eq!(serde_json::to_value(error.code()).unwrap(), code);
+            assert_eq!(error.code().status(), status, "{}", code);
+            assert_eq!(error.status_code(), status, "{}", code);
+        }
+    }
+
+    #[test]
+    fn db_codes_have_statuses() {
+        let cases = [
+            (ErrorCode::DbUnavailable, "db.unavailable", 503),
+            (ErrorCode::Db, "db.error", 500),
+            (ErrorCode::NotFound, "db.not_found", 404),
+            (ErrorCode::UniqueViolation, "db.unique_violation", 409),
+            (
+                ErrorCode::ForeignKeyViolation,
+                "db.foreign_key_violation",
+                409,
+            ),
+            (ErrorCode::NotNullViolation, "db.not_null_violation", 422),
+            (ErrorCode::CheckViolation, "db.check_violation", 422),
+        ];
+        for (code, name, status) in cases.iter() {
+            assert_eq!(serde_json::to_value(code).unwrap(), *name);
+            assert_eq!(code.status().as_u16(), *status, "{}", name);
+        }
+    }
+
+    #[test]
+    fn errors_without_postgres_source_are_db_errors() {
+        let error = io::Error::new(io::ErrorKind::Other, "unexpected number of rows");
+        assert_eq!(db_code(&error), ErrorCode::Db);
+    }
+}
@@ -0,0 +1,371 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+use deadpool_postgres::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_postgres::types::ToSql;
+
+use crate::dbo::DBObject;
+use crate::error::ServiceError;
+use crate::scoping::RowScope;
+
+/// A list paged, sorted and filtered by Postgres instead of in memory. `rows`
+/// selects the list rows with the `owner_company` and `owner_department`
+/// columns used for row scoping; those are left out of the reply.
+pub struct SqlList {
+    name: &'static str,
+    rows: &'static str,
+    /// Fields allowed in `sort` and `filter`.
+    fields: &'static [&'static str],
+}
+
+const SQL_LISTS: [SqlList; 2] = [
+    SqlList {
+        name: "ContactList",
+        rows: "
+            SELECT c.id, c.name, c.company_id, co.name AS company_name,
+                po.name AS post_name,
+                array_remove(array_agg(DISTINCT ph.phone), NULL) AS phones,
+                array_remove(array_agg(DISTINCT fx.phone), NULL) AS faxes,
+                c.company_id AS owner_company, c.department_id AS owner_department
+            FROM contacts c
+            LEFT JOIN companies co ON co.id = c.company_id
+            LEFT JOIN posts po ON po.id = c.post_id
+            LEFT JOIN phones ph ON ph.contact_id = c.id AND ph.fax = false
+            LEFT JOIN phones fx ON fx.contact_id = c.id AND fx.fax = true
+            WHERE c.deleted_at IS NULL
+            GROUP BY c.id, co.name, po.name",
+        fields: &["id", "name", "company_id", "company_name", "post_name"],
+    },
+    SqlList {
+        name: "PracticeList",
+        rows: "
+            SELECT p.id, p.company_id, co.name AS company_name, k.name AS kind_name,
+                k.short_name AS kind_short_name, p.topic, p.date_of_practice,
+                to_char(p.date_of_practice, 'DD.MM.YYYY') AS date_str,
+                p.company_id AS owner_company, NULL::bigint AS owner_department
+            FROM practices p
+            LEFT JOIN companies co ON co.id = p.company_id
+            LEFT JOIN kinds k ON k.id = p.kind_id
+            WHERE p.deleted_at IS NULL",
+        fields: &[
+            "id",
+            "company_id",
+            "company_name",
+            "kind_name",
+            "topic",
+            "date_of_practice",
+        ],
+    },
+];
+
+/// A list requested either by name (`"ContactList"`) or with paging options
+/// (`{"name": "ContactList", "offset": 0, "limit": 50, "sort": "name"}`).
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ListQuery {
+    Name(String),
+    Page(ListOptions),
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ListOptions {
+    pub name: String,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+    pub sort: Option<String>,
+    #[serde(default)]
+    pub desc: bool,
+    /// Field values to match. Strings match case-insensitive substrings,
+    /// other values must be equal.
+    #[serde(default)]
+    pub filter: HashMap<String, Value>,
+}
+
+impl ListQuery {
+    pub fn name(&self) -> &str {
+        match self {
+            ListQuery::Name(name) => name,
+            ListQuery::Page(options) => &options.name,
+        }
+    }
+}
+
+impl fmt::Display for ListQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListQuery::Name(name) => write!(f, "{}", name),
+            ListQuery::Page(options) => write!(
+                f,
+                "{} offset {} limit {:?}",
+                options.name, options.offset, options.limit
+            ),
+        }
+    }
+}
+
+fn matches(row: &Value, filter: &HashMap<String, Value>) -> bool {
+    filter
+        .iter()
+        .all(|(field, wanted)| match (&row[field], wanted) {
+            (Value::String(value), Value::String(wanted)) => value
+                .to_lowercase()
+                .contains(wanted.to_lowercase().as_str()),
+            (value, wanted) => value == wanted,
+        })
+}
+
+/// Orders numbers and strings naturally and puts empty values last in both
+/// directions.
+fn compare(a: &Value, b: &Value, desc: bool) -> Ordering {
+    let order = match (a, b) {
+        (Value::Null, Value::Null) => return Ordering::Equal,
+        (Value::Null, _) => return Ordering::Greater,
+        (_, Value::Null) => return Ordering::Less,
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Value::String(a), Value::String(b)) => a.to_lowercase().cmp(&b.to_lowercase()),
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        _ => Ordering::Equal,
+    };
+    if desc {
+        order.reverse()
+    } else {
+        order
+    }
+}
+
+/// `ILIKE` pattern matching `text` anywhere, with `%`, `_` and `\` taken
+/// literally.
+pub fn contains_pattern(text: &str) -> String {
+    let mut pattern = String::with_capacity(text.len() + 2);
+    pattern.push('%');
+    for c in text.chars() {
+        if c == '%' || c == '_' || c == '\\' {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// Filters, sorts and cuts a page out of a list object. Returns the page and
+/// the number of rows that passed the filter.
+pub fn page(object: DBObject, options: &ListOptions) -> Result<(DBObject, usize), ServiceError> {
+    let name = object.name();
+    let mut value = serde_json::to_value(object)?;
+    let rows = match value.get_mut(name.as_str()).and_then(Value::as_array_mut) {
+        Some(rows) => rows,
+        None => return Err(ServiceError::BadRequest(format!("{} is not a list", name))),
+    };
+    rows.retain(|row| matches(row, &options.filter));
+    if let Some(field) = &options.sort {
+        rows.sort_by(|a, b| compare(&a[field], &b[field], options.desc));
+    }
+    let total = rows.len();
+    let limit = options.limit.unwrap_or(total);
+    let page: Vec<Value> = rows.drain(..).skip(options.offset).take(limit).collect();
+    *rows = page;
+    Ok((serde_json::from_value(value)?, total))
+}
+
+pub fn sql_list(name: &str) -> Option<&'static SqlList> {
+    SQL_LISTS.iter().find(|list| list.name == name)
+}
+
+type Params = Vec<Box<dyn ToSql + Sync>>;
+
+fn field<'a>(list: &SqlList, name: &'a str) -> Result<&'a str, ServiceError> {
+    if list.fields.contains(&name) {
+        Ok(name)
+    } else {
+        Err(ServiceError::BadRequest(format!(
+            "{} cannot be sorted or filtered by {}",
+            list.name, name
+        )))
+    }
+}
+
+/// Builds the query selecting the filtered rows inside the scope, bound to
+/// `$1` and `$2`, followed by the filter values.
+fn filtered(list: &SqlList, options: &ListOptions) -> Result<(String, Params), ServiceError> {
+    let mut params: Params = Vec::new();
+    let mut conditions = vec![
+        "($1::bigint[] IS NULL OR owner_company = ANY($1) OR owner_department = ANY($2))"
+            .to_string(),
+    ];
+    let mut filters: Vec<(&String, &Value)> = options.filter.iter().collect();
+    filters.sort_by_key(|(name, _)| name.as_str());
+    for (name, wanted) in filters {
+        let name = field(list, name)?;
+        let n = params.len() + 3;
+        match wanted {
+            Value::String(text) => {
+                conditions.push(format!("{}::text ILIKE ${}", name, n));
+                params.push(Box::new(contains_pattern(text)));
+            }
+            wanted => {
+                conditions.push(format!("to_jsonb({}) = ${}::jsonb", name, n));
+                params.push(Box::new(wanted.clone()));
+            }
+        }
+    }
+    Ok((
+        format!(
+            "WITH list AS ({}) SELECT * FROM list WHERE {}",
+            list.rows,
+            conditions.join(" AND ")
+        ),
+        params,
+    ))
+}
+
+fn page_sql(
+    list: &SqlList,
+    options: &ListOptions,
+) -> Result<(String, String, Params), ServiceError> {
+    let (rows, params) = filtered(list, options)?;
+    let order = match &options.sort {
+        Some(sort) => format!(
+            "{} {} NULLS LAST, id",
+            field(list, sort)?,
+            if options.desc { "DESC" } else { "ASC" }
+        ),
+        None => "id".to_string(),
+    };
+    let n = params.len() + 3;
+    let page = format!(
+        "SELECT to_jsonb(r) - 'owner_company' - 'owner_department' FROM ({}) r
+        ORDER BY {} LIMIT ${} OFFSET ${}",
+        rows,
+        order,
+        n,
+        n + 1
+    );
+    let count = format!("SELECT count(*) FROM ({}) r", rows);
+    Ok((page, count, params))
+}
+
+/// Loads one page of a list from Postgres. Returns the page and the number of
+/// rows that passed the filter.
+pub async fn sql_page(
+    list: &SqlList,
+    options: &ListOptions,
+    scope: &Option<RowScope>,
+    client: &Client,
+) -> Result<(DBObject, usize), ServiceError> {
+    let (page, count, filters) = page_sql(list, options)?;
+    let companies = scope.as_ref().map(|scope| scope.companies.clone());
+    let departments = scope.as_ref().map(|scope| scope.departments.clone());
+    let limit = options.limit.map_or(i64::MAX, |limit| limit as i64);
+    let offset = options.offset as i64;
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&companies, &departments];
+    params.extend(filters.iter().map(|param| param.as_ref()));
+    let total: i64 = client.query_one(count.as_str(), &params).await?.get(0);
+    params.push(&limit);
+    params.push(&offset);
+    let rows = client.query(page.as_str(), &params).await?;
+    let rows: Vec<Value> = rows.iter().map(|row| row.get(0)).collect();
+    let object = serde_json::from_value(json!({ list.name: rows }))?;
+    Ok((object, total as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn hits(object: DBObject) -> Vec<i64> {
+        match object {
+            DBObject::SearchHits(hits) => hits.into_iter().map(|hit| hit.id).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn list() -> DBObject {
+        serde_json::from_value(json!({"SearchHits": [
+            {"name": "Contact", "id": 1, "title": "Bravo", "rank": 0.5},
+            {"name": "Contact", "id": 2, "title": "alpha", "rank": 0.9},
+            {"name": "Company", "id": 3, "title": "Charlie", "rank": 0.1},
+        ]}))
+        .unwrap()
+    }
+
+    fn options(value: Value) -> ListOptions {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn filter_matches_substrings_and_values() {
+        let (rows, total) = page(
+            list(),
+            &options(json!({"name": "SearchHits", "filter": {"title": "AR"}})),
+        )
+        .unwrap();
+        assert_eq!((hits(rows), total), (vec![3], 1));
+        let (rows, _) = page(
+            list(),
+            &options(json!({"name": "SearchHits", "filter": {"name": "Contact", "id": 2}})),
+        )
+        .unwrap();
+        assert_eq!(hits(rows), vec![2]);
+    }
+
+    #[test]
+    fn sort_and_page() {
+        let (rows, total) = page(
+            list(),
+            &options(json!({"name": "SearchHits", "sort": "title", "offset": 1, "limit": 1})),
+        )
+        .unwrap();
+        assert_eq!((hits(rows), total), (vec![1], 3));
+        let (rows, _) = page(
+            list(),
+            &options(json!({"name": "SearchHits", "sort": "rank", "desc": true})),
+        )
+        .unwrap();
+        assert_eq!(hits(rows), vec![2, 1, 3]);
+    }
+
+    #[test]
+    fn empty_values_sort_last_both_ways() {
+        let mut values = vec![json!(null), json!(2), json!(1)];
+        values.sort_by(|a, b| compare(a, b, false));
+        assert_eq!(values, vec![json!(1), json!(2), json!(null)]);
+        values.sort_by(|a, b| compare(a, b, true));
+        assert_eq!(values, vec![json!(2), json!(1), json!(null)]);
+    }
+
+    #[test]
+    fn sql_lists_only_sort_and_filter_known_fields() {
+        let contacts = sql_list("ContactList").unwrap();
+        let (page, count, params) = page_sql(
+            contacts,
+            &options(json!({
+                "name": "ContactList",
+                "sort": "company_name",
+                "desc": true,
+                "filter": {"name": "ann", "company_id": 4},
+            })),
+        )
+        .unwrap();
+        assert_eq!(params.len(), 2);
+        assert!(page.contains("ORDER BY company_name DESC NULLS LAST, id LIMIT $5 OFFSET $6"));
+        assert!(count.contains("company_id) = $3::jsonb AND name::text ILIKE $4"));
+        let bad = options(json!({"name": "ContactList", "sort": "id; DROP TABLE users"}));
+        assert!(page_sql(contacts, &bad).is_err());
+        assert!(sql_list("KindList").is_none());
+    }
+
+    #[test]
+    fn like_wildcards_are_escaped() {
+        assert_eq!(contains_pattern("50%_a\\b"), "%50\\%\\_a\\\\b%");
+    }
+}
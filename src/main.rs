@@ -4,18 +4,30 @@ use actix::Actor;
 use actix_cors::Cors;
 use actix_web::{middleware, web, App, HttpServer};
 
-use auth::{check_auth, login};
+use auth::{check_auth, login, logout, refresh};
 use db::{check_global, global_init};
 use server::Server;
 use session::wsroute;
 
+mod audit;
 mod auth;
 mod db;
 mod dbo;
 mod error;
+mod listing;
+mod notify;
+mod password;
+mod registry;
+mod roles;
+mod scoping;
+mod search;
 mod server;
 mod session;
+mod throttle;
+mod token;
+mod trash;
 mod users;
+mod validate;
 
 #[actix_rt::main]
 async fn main() -> io::Result<()> {
@@ -37,6 +49,8 @@ async fn main() -> io::Result<()> {
             .wrap(middleware::Logger::default())
             .service(web::resource("/api/go/check").route(web::post().to(check_auth)))
             .service(web::resource("/api/go/login").route(web::post().to(login)))
+            .service(web::resource("/api/go/refresh").route(web::post().to(refresh)))
+            .service(web::resource("/api/go/logout").route(web::post().to(logout)))
             .service(web::resource("/api/go").route(web::get().to(wsroute)))
     })
     .bind(addr)?
@@ -0,0 +1,180 @@
+use std::collections::HashSet;
+
+use deadpool_postgres::Client;
+use futures::channel::mpsc::{self, UnboundedReceiver};
+use futures::{future, stream, StreamExt};
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use tokio_postgres::{AsyncMessage, NoTls};
+
+use crate::error::ServiceError;
+use crate::registry::ENTITIES;
+use crate::server::Change;
+
+const CHANNEL: &str = "rugo_changes";
+
+/// Sends the entity named by the first trigger argument. The second one tells
+/// whether the table is soft deleted, where moving a row in and out of the
+/// trash is announced as `Delete` and `Restore` and removing it as `Purge`,
+/// the same commands the sessions of this process get.
+const CREATE_FUNCTION: &str = "
+    CREATE OR REPLACE FUNCTION rugo_notify() RETURNS trigger AS $$
+    DECLARE
+        item_id BIGINT;
+        command TEXT := initcap(TG_OP);
+    BEGIN
+        IF TG_OP = 'DELETE' THEN
+            item_id := OLD.id;
+        ELSE
+            item_id := NEW.id;
+        END IF;
+        IF TG_ARGV[1]::boolean THEN
+            IF TG_OP = 'DELETE' THEN
+                command := 'Purge';
+            ELSIF TG_OP = 'UPDATE' THEN
+                IF OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN
+                    command := 'Delete';
+                ELSIF OLD.deleted_at IS NOT NULL AND NEW.deleted_at IS NULL THEN
+                    command := 'Restore';
+                END IF;
+            END IF;
+        END IF;
+        PERFORM pg_notify('rugo_changes', json_build_object(
+            'entity', TG_ARGV[0],
+            'id', item_id,
+            'command', command,
+            'origin', current_setting('rugo.origin', true)
+        )::text);
+        RETURN NULL;
+    END;
+    $$ LANGUAGE plpgsql;
+";
+
+/// Marks the transactions of this process, so its own notifications are not
+/// pushed a second time.
+static ORIGIN: Lazy<String> = Lazy::new(|| format!("{:016x}", rand::random::<u64>()));
+
+#[derive(Deserialize)]
+struct Payload {
+    entity: String,
+    id: i64,
+    command: String,
+    origin: Option<String>,
+}
+
+/// Installs the trigger on the tables that lack it. Tables that have it are
+/// left alone, so a restart takes no table locks.
+pub async fn init(client: &Client) -> Result<(), ServiceError> {
+    client.batch_execute(CREATE_FUNCTION).await?;
+    let rows = client
+        .query(
+            "SELECT c.relname::text FROM pg_trigger t JOIN pg_class c ON c.oid = t.tgrelid
+            WHERE t.tgname = 'rugo_notify' AND c.relnamespace = current_schema()::regnamespace",
+            &[],
+        )
+        .await?;
+    let installed: HashSet<String> = rows.iter().map(|row| row.get(0)).collect();
+    for entity in ENTITIES.iter() {
+        if installed.contains(entity.table) {
+            continue;
+        }
+        client
+            .batch_execute(
+                format!(
+                    "CREATE TRIGGER rugo_notify AFTER INSERT OR UPDATE OR DELETE ON {table}
+                    FOR EACH ROW EXECUTE PROCEDURE rugo_notify('{entity}', '{soft}');",
+                    table = entity.table,
+                    entity = entity.name,
+                    soft = entity.soft_delete
+                )
+                .as_str(),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// Opens a transaction tagged with the origin of this process.
+pub async fn begin(client: &Client) -> Result<(), ServiceError> {
+    client
+        .batch_execute(
+            format!(
+                "BEGIN; SELECT set_config('rugo.origin', '{}', true);",
+                *ORIGIN
+            )
+            .as_str(),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Changes made by other processes or directly in the database.
+fn remote_change(payload: &str) -> Option<Change> {
+    let payload: Payload = match serde_json::from_str(payload) {
+        Ok(payload) => payload,
+        Err(err) => {
+            warn!("bad notification {}: {}", payload, err);
+            return None;
+        }
+    };
+    if payload.origin.as_ref() == Some(&*ORIGIN) {
+        return None;
+    }
+    Some(Change {
+        entity: payload.entity,
+        id: payload.id,
+        command: payload.command,
+    })
+}
+
+/// Listens on a dedicated connection. The receiver ends when the connection
+/// is lost; the returned client must be kept for as long as it is listened.
+pub async fn listen() -> Result<(tokio_postgres::Client, UnboundedReceiver<Change>), ServiceError> {
+    let url = dotenv::var("DATABASE_URL").map_err(|_| ServiceError::InternalServerError)?;
+    let (client, mut connection) = tokio_postgres::connect(&url, NoTls).await?;
+    let (tx, rx) = mpsc::unbounded();
+    let messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+    actix_rt::spawn(messages.for_each(move |message| {
+        match message {
+            Ok(AsyncMessage::Notification(notification)) => {
+                if let Some(change) = remote_change(notification.payload()) {
+                    let _ = tx.unbounded_send(change);
+                }
+            }
+            Ok(_) => (),
+            Err(err) => warn!("listen connection: {}", err),
+        }
+        future::ready(())
+    }));
+    client
+        .batch_execute(format!("LISTEN {}", CHANNEL).as_str())
+        .await?;
+    Ok((client, rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(origin: Option<&str>) -> String {
+        serde_json::json!({
+            "entity": "Contact",
+            "id": 42,
+            "command": "Delete",
+            "origin": origin,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn own_changes_are_not_pushed_twice() {
+        assert!(remote_change(&payload(Some(ORIGIN.as_str()))).is_none());
+        let change = remote_change(&payload(Some("0000000000000000"))).unwrap();
+        assert_eq!(change.entity, "Contact");
+        assert_eq!(change.id, 42);
+        assert_eq!(change.command, "Delete");
+        assert!(remote_change(&payload(None)).is_some());
+        assert!(remote_change("not json").is_none());
+    }
+}
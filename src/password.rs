@@ -0,0 +1,99 @@
+use actix_web::{error::BlockingError, web};
+use argon2::{Config, Variant};
+use once_cell::sync::Lazy;
+use rand::{thread_rng, Rng};
+
+use crate::error::ServiceError;
+
+const PREFIX: &str = "$argon2";
+
+static DUMMY_HASH: Lazy<String> = Lazy::new(|| encode("dummy key").unwrap_or_default());
+
+pub fn is_hashed(key: &str) -> bool {
+    key.starts_with(PREFIX)
+}
+
+pub async fn hash(key: &str) -> Result<String, ServiceError> {
+    let key = key.to_string();
+    blocking(move || encode(&key)).await?
+}
+
+/// Checks a key against the stored value, which is either an argon2 hash or
+/// a legacy plain text key.
+pub async fn verify(stored: &str, key: &str) -> Result<bool, ServiceError> {
+    let (stored, key) = (stored.to_string(), key.to_string());
+    blocking(move || compare(&stored, &key)).await
+}
+
+/// Burns the same time as a real verification, used for unknown usernames so
+/// that response timing does not reveal which accounts exist.
+pub async fn verify_dummy(key: &str) -> Result<(), ServiceError> {
+    let key = key.to_string();
+    blocking(move || {
+        compare(&DUMMY_HASH, &key);
+    })
+    .await
+}
+
+/// Runs argon2 on the blocking thread pool, so a login does not stall the
+/// other requests of its worker.
+async fn blocking<T, F>(f: F) -> Result<T, ServiceError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(move || Ok::<_, ServiceError>(f()))
+        .await
+        .map_err(|err| match err {
+            BlockingError::Error(err) => err,
+            BlockingError::Canceled => ServiceError::InternalServerError,
+        })
+}
+
+fn encode(key: &str) -> Result<String, ServiceError> {
+    let salt: [u8; 16] = thread_rng().gen();
+    let config = Config {
+        variant: Variant::Argon2id,
+        ..Config::default()
+    };
+    argon2::hash_encoded(key.as_bytes(), &salt, &config)
+        .map_err(|_| ServiceError::InternalServerError)
+}
+
+fn compare(stored: &str, key: &str) -> bool {
+    if is_hashed(stored) {
+        argon2::verify_encoded(stored, key.as_bytes()).unwrap_or(false)
+    } else {
+        constant_time_eq(stored.as_bytes(), key.as_bytes())
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_only_for_same_bytes() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret1"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn verifies_hashed_and_legacy_keys() {
+        let hashed = encode("secret").unwrap();
+        assert!(is_hashed(&hashed));
+        assert!(compare(&hashed, "secret"));
+        assert!(!compare(&hashed, "wrong"));
+        assert!(compare("secret", "secret"));
+        assert!(!compare("secret", "wrong"));
+    }
+}
@@ -0,0 +1,330 @@
+use deadpool_postgres::Client;
+use futures::future::LocalBoxFuture;
+
+use rpel::certificate::{Certificate, CertificateList};
+use rpel::company::{Company, CompanyList};
+use rpel::contact::{Contact, ContactList};
+use rpel::department::{Department, DepartmentList};
+use rpel::education::{Education, EducationList, EducationShort};
+use rpel::kind::{Kind, KindList};
+use rpel::post::{Post, PostList};
+use rpel::practice::{Practice, PracticeList, PracticeShort};
+use rpel::rank::{Rank, RankList};
+use rpel::scope::{Scope, ScopeList};
+use rpel::select::SelectItem;
+use rpel::siren::{Siren, SirenList};
+use rpel::siren_type::{SirenType, SirenTypeList};
+use rpel::user::{User, UserList};
+
+use crate::dbo::DBObject;
+use crate::error::ServiceError;
+use crate::users::{hash_key, hide_key, hide_keys};
+
+type Handler<'a, T> = LocalBoxFuture<'a, Result<T, ServiceError>>;
+
+/// An entity stored one row per item, with the handlers of every item
+/// command.
+pub struct Entity {
+    pub name: &'static str,
+    /// Other names accepted in `Item` objects.
+    pub aliases: &'static [&'static str],
+    pub table: &'static str,
+    /// Deleted into the trash instead of being removed.
+    pub soft_delete: bool,
+    pub get: for<'a> fn(&'a Client, i64) -> Handler<'a, DBObject>,
+    pub insert: for<'a> fn(&'a Client, DBObject) -> Handler<'a, i64>,
+    pub update: for<'a> fn(&'a Client, DBObject) -> Handler<'a, i64>,
+    pub delete: for<'a> fn(&'a Client, i64) -> Handler<'a, i64>,
+}
+
+/// A list or select object, the `DBObject` variant it loads and the entity of
+/// its rows.
+pub struct List {
+    pub name: &'static str,
+    pub object: &'static str,
+    pub entity: &'static str,
+    pub load: for<'a> fn(&'a Client) -> Handler<'a, DBObject>,
+}
+
+fn bad_object() -> ServiceError {
+    ServiceError::BadRequest("bad item object".to_string())
+}
+
+macro_rules! entity {
+    ($variant:ident, $table:literal, $aliases:expr) => {
+        entity!(@ $variant, $table, $aliases, |item| item, |item: $variant| item)
+    };
+    ($variant:ident, $table:literal, $aliases:expr, boxed) => {
+        entity!(@ $variant, $table, $aliases, Box::new, |item: Box<$variant>| *item)
+    };
+    (@ $variant:ident, $table:literal, $aliases:expr, $wrap:expr, $unwrap:expr) => {
+        Entity {
+            name: stringify!($variant),
+            aliases: $aliases,
+            table: $table,
+            soft_delete: true,
+            get: |client, id| {
+                Box::pin(async move {
+                    Ok(DBObject::$variant(($wrap)($variant::get(client, id).await?)))
+                })
+            },
+            insert: |client, object| {
+                Box::pin(async move {
+                    match object {
+                        DBObject::$variant(item) => {
+                            Ok($variant::insert(client, ($unwrap)(item)).await?.id)
+                        }
+                        _ => Err(bad_object()),
+                    }
+                })
+            },
+            update: |client, object| {
+                Box::pin(async move {
+                    match object {
+                        DBObject::$variant(item) => {
+                            Ok($variant::update(client, ($unwrap)(item)).await? as i64)
+                        }
+                        _ => Err(bad_object()),
+                    }
+                })
+            },
+            delete: |client, id| {
+                Box::pin(async move { Ok($variant::delete(client, id).await? as i64) })
+            },
+        }
+    };
+}
+
+macro_rules! list {
+    ($name:literal, $entity:literal, $variant:ident, $load:path) => {
+        list!($name, $entity, $variant, |client| $load(client))
+    };
+    ($name:literal, $entity:literal, $variant:ident, |$client:ident| $load:expr) => {
+        List {
+            name: $name,
+            object: stringify!($variant),
+            entity: $entity,
+            load: |$client| Box::pin(async move { Ok(DBObject::$variant($load.await?)) }),
+        }
+    };
+}
+
+pub static ENTITIES: [Entity; 13] = [
+    entity!(Certificate, "certificates", &[]),
+    entity!(Company, "companies", &[], boxed),
+    entity!(Contact, "contacts", &[], boxed),
+    entity!(Department, "departments", &[]),
+    entity!(Education, "educations", &[]),
+    entity!(Kind, "kinds", &[]),
+    entity!(Post, "posts", &[]),
+    entity!(Practice, "practices", &[]),
+    entity!(Rank, "ranks", &[]),
+    entity!(Scope, "scopes", &[]),
+    entity!(Siren, "sirens", &[], boxed),
+    entity!(SirenType, "siren_types", &["Siren_type"]),
+    // Users keep their keys hidden and hashed and are removed for good, so
+    // their tokens and logins go with them.
+    Entity {
+        name: "User",
+        aliases: &[],
+        table: "users",
+        soft_delete: false,
+        get: |client, id| {
+            Box::pin(async move { Ok(DBObject::User(hide_key(User::get(client, id).await?))) })
+        },
+        insert: |client, object| {
+            Box::pin(async move {
+                match object {
+                    DBObject::User(item) => Ok(User::insert(client, hash_key(item, client).await?)
+                        .await?
+                        .id),
+                    _ => Err(bad_object()),
+                }
+            })
+        },
+        update: |client, object| {
+            Box::pin(async move {
+                match object {
+                    DBObject::User(item) => {
+                        Ok(User::update(client, hash_key(item, client).await?).await? as i64)
+                    }
+                    _ => Err(bad_object()),
+                }
+            })
+        },
+        delete: |client, id| Box::pin(async move { Ok(User::delete(client, id).await? as i64) }),
+    },
+];
+
+/// Every list and select object. `EducationShort` and `PracticeShort` are
+/// accepted as the names of the objects `EducationNear` and `PracticeNear`
+/// return; `SelectItem` is only ever a result, each select picks its own rows.
+pub static LISTS: [List; 26] = [
+    list!(
+        "CertificateList",
+        "Certificate",
+        CertificateList,
+        CertificateList::get_all
+    ),
+    list!("CompanyList", "Company", CompanyList, CompanyList::get_all),
+    list!(
+        "CompanySelect",
+        "Company",
+        SelectItem,
+        SelectItem::company_all
+    ),
+    list!("ContactList", "Contact", ContactList, ContactList::get_all),
+    list!(
+        "ContactSelect",
+        "Contact",
+        SelectItem,
+        SelectItem::contact_all
+    ),
+    list!(
+        "DepartmentList",
+        "Department",
+        DepartmentList,
+        DepartmentList::get_all
+    ),
+    list!(
+        "DepartmentSelect",
+        "Department",
+        SelectItem,
+        SelectItem::department_all
+    ),
+    list!(
+        "EducationList",
+        "Education",
+        EducationList,
+        EducationList::get_all
+    ),
+    list!(
+        "EducationNear",
+        "Education",
+        EducationShort,
+        EducationShort::get_near
+    ),
+    list!(
+        "EducationShort",
+        "Education",
+        EducationShort,
+        EducationShort::get_near
+    ),
+    list!("KindList", "Kind", KindList, KindList::get_all),
+    list!("KindSelect", "Kind", SelectItem, SelectItem::kind_all),
+    list!("PostList", "Post", PostList, PostList::get_all),
+    list!("PostSelect", "Post", SelectItem, |client| {
+        SelectItem::post_all(client, false)
+    }),
+    list!("PostGoSelect", "Post", SelectItem, |client| {
+        SelectItem::post_all(client, true)
+    }),
+    list!(
+        "PracticeList",
+        "Practice",
+        PracticeList,
+        PracticeList::get_all
+    ),
+    list!(
+        "PracticeNear",
+        "Practice",
+        PracticeShort,
+        PracticeShort::get_near
+    ),
+    list!(
+        "PracticeShort",
+        "Practice",
+        PracticeShort,
+        PracticeShort::get_near
+    ),
+    list!("RankList", "Rank", RankList, RankList::get_all),
+    list!("RankSelect", "Rank", SelectItem, SelectItem::rank_all),
+    list!("ScopeList", "Scope", ScopeList, ScopeList::get_all),
+    list!("ScopeSelect", "Scope", SelectItem, SelectItem::scope_all),
+    list!("SirenList", "Siren", SirenList, SirenList::get_all),
+    list!(
+        "SirenTypeList",
+        "SirenType",
+        SirenTypeList,
+        SirenTypeList::get_all
+    ),
+    list!(
+        "SirenTypeSelect",
+        "SirenType",
+        SelectItem,
+        SelectItem::siren_type_all
+    ),
+    list!("UserList", "User", UserList, |client| async move {
+        Ok::<_, ServiceError>(hide_keys(UserList::get_all(client).await?))
+    }),
+];
+
+/// Entity named `name` or one of its aliases.
+pub fn entity(name: &str) -> Option<&'static Entity> {
+    ENTITIES
+        .iter()
+        .find(|entity| entity.name == name || entity.aliases.contains(&name))
+}
+
+pub fn list(name: &str) -> Option<&'static List> {
+    LISTS.iter().find(|list| list.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn is_variant(name: &str, payload: serde_json::Value) -> bool {
+        match serde_json::from_value::<DBObject>(json!({ name: payload })) {
+            Ok(object) => object.name() == name,
+            Err(err) => !err.to_string().contains("unknown variant"),
+        }
+    }
+
+    #[test]
+    fn every_entity_and_list_is_a_db_object() {
+        for entity in ENTITIES.iter() {
+            assert!(is_variant(entity.name, json!({})), "{}", entity.name);
+        }
+        for list in LISTS.iter() {
+            assert!(is_variant(list.object, json!([])), "{}", list.name);
+        }
+    }
+
+    #[test]
+    fn every_entity_has_a_table_and_lists() {
+        let names: HashSet<&str> = ENTITIES.iter().map(|entity| entity.name).collect();
+        let tables: HashSet<&str> = ENTITIES.iter().map(|entity| entity.table).collect();
+        assert_eq!(names.len(), ENTITIES.len());
+        assert_eq!(tables.len(), ENTITIES.len());
+        for entity in ENTITIES.iter() {
+            let list = list(&format!("{}List", entity.name)).expect(entity.name);
+            assert_eq!(list.entity, entity.name);
+        }
+        for list in LISTS.iter() {
+            assert!(names.contains(list.entity), "{}", list.name);
+        }
+    }
+
+    #[test]
+    fn aliases_resolve_to_one_entity() {
+        assert_eq!(entity("Siren_type").map(|e| e.name), Some("SirenType"));
+        assert_eq!(entity("SirenType").map(|e| e.table), Some("siren_types"));
+        assert_eq!(
+            list("EducationShort").map(|l| l.object),
+            Some("EducationShort")
+        );
+        assert_eq!(list("PracticeShort").map(|l| l.entity), Some("Practice"));
+        assert!(entity("SelectItem").is_none());
+        let removed: Vec<&str> = ENTITIES
+            .iter()
+            .filter(|entity| !entity.soft_delete)
+            .map(|entity| entity.name)
+            .collect();
+        assert_eq!(removed, vec!["User"]);
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use deadpool_postgres::Client;
+use log::warn;
+
+use crate::error::ServiceError;
+
+const CREATE_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS roles (
+        id BIGINT PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        permissions TEXT[] NOT NULL DEFAULT '{}'
+    );
+";
+
+const USER: &str = "User";
+
+/// Entities left out of wildcard grants.
+const ADMIN_ENTITIES: [&str; 3] = [USER, "Audit", "Trash"];
+
+/// Permissions granted to the threshold roles of older databases, in the order
+/// of the thresholds.
+const LEGACY: [&str; 8] = [
+    "read",
+    "insert",
+    "update",
+    "delete",
+    "read:User",
+    "insert:User",
+    "update:User",
+    "delete:User",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Read,
+    Insert,
+    Update,
+    Delete,
+}
+
+impl FromStr for Action {
+    type Err = ServiceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Action::Read),
+            "insert" => Ok(Action::Insert),
+            "update" => Ok(Action::Update),
+            "delete" => Ok(Action::Delete),
+            e => Err(ServiceError::BadRequest(format!("bad action: {}", e))),
+        }
+    }
+}
+
+/// A grant of one action, either on a single entity (`update:Siren`) or on
+/// every entity except users, the audit log and the trash (`update`). Those
+/// always need an explicit grant such as `read:Audit`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Permission {
+    pub action: Action,
+    pub entity: Option<String>,
+}
+
+impl Permission {
+    pub fn new(action: Action, entity: &str) -> Permission {
+        Permission {
+            action,
+            entity: Some(entity.to_string()),
+        }
+    }
+
+    pub fn any(action: Action) -> Permission {
+        Permission {
+            action,
+            entity: None,
+        }
+    }
+}
+
+impl FromStr for Permission {
+    type Err = ServiceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.find(':') {
+            Some(pos) => Ok(Permission::new(s[..pos].parse()?, &s[pos + 1..])),
+            None => Ok(Permission::any(s.parse()?)),
+        }
+    }
+}
+
+pub fn allows(permissions: &HashSet<Permission>, action: Action, entity: &str) -> bool {
+    permissions.contains(&Permission::new(action, entity))
+        || (!ADMIN_ENTITIES.contains(&entity) && permissions.contains(&Permission::any(action)))
+}
+
+pub async fn init(client: &Client) -> Result<(), ServiceError> {
+    client.batch_execute(CREATE_TABLE).await?;
+    Ok(())
+}
+
+/// Loads named role bundles, keyed by the role id stored in `users.role`.
+pub async fn load(client: &Client) -> Result<HashMap<i64, HashSet<Permission>>, ServiceError> {
+    let rows = client
+        .query("SELECT id, name, permissions FROM roles", &[])
+        .await?;
+    let mut roles = HashMap::new();
+    for row in rows {
+        let id: i64 = row.get(0);
+        let name: String = row.get(1);
+        let granted: Vec<String> = row.get(2);
+        let permissions: HashSet<Permission> = granted
+            .iter()
+            .filter_map(|permission| match permission.parse() {
+                Ok(permission) => Some(permission),
+                Err(err) => {
+                    warn!("role {}: {}", name, err);
+                    None
+                }
+            })
+            .collect();
+        roles.insert(id, permissions);
+    }
+    Ok(roles)
+}
+
+/// Permissions of a user role that has no row in the `roles` table. Older
+/// databases store the role as a threshold, where each step up grants one
+/// more command.
+pub fn legacy(role: i64) -> HashSet<Permission> {
+    LEGACY
+        .iter()
+        .enumerate()
+        .filter(|(n, _)| role >> (n + 1) > 0)
+        .filter_map(|(_, permission)| permission.parse().ok())
+        .collect()
+}
+
+pub fn resolve(roles: &HashMap<i64, HashSet<Permission>>, role: i64) -> HashSet<Permission> {
+    roles.get(&role).cloned().unwrap_or_else(|| legacy(role))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn granted(permissions: &[&str]) -> HashSet<Permission> {
+        permissions.iter().map(|p| p.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn legacy_roles_keep_thresholds() {
+        assert!(legacy(1).is_empty());
+        assert_eq!(legacy(2), granted(&["read"]));
+        assert_eq!(legacy(511), granted(&LEGACY));
+        assert_eq!(
+            legacy(64),
+            granted(&[
+                "read",
+                "insert",
+                "update",
+                "delete",
+                "read:User",
+                "insert:User"
+            ])
+        );
+    }
+
+    #[test]
+    fn permissions_have_one_form() {
+        assert_eq!(
+            "read:User".parse::<Permission>().unwrap(),
+            Permission::new(Action::Read, USER)
+        );
+        assert_eq!(
+            "delete".parse::<Permission>().unwrap(),
+            Permission::any(Action::Delete)
+        );
+        assert!("user_read".parse::<Permission>().is_err());
+    }
+}
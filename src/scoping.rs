@@ -0,0 +1,214 @@
+use std::collections::{HashMap, HashSet};
+
+use deadpool_postgres::Client;
+use serde_json::Value;
+
+use crate::db::{Command, Object, Versioned};
+use crate::dbo::{get_list, list_entity, DBObject};
+use crate::error::ServiceError;
+use crate::server::Topic;
+
+const CREATE_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS user_scopes (
+        user_id BIGINT NOT NULL,
+        company_id BIGINT,
+        department_id BIGINT
+    );
+    CREATE INDEX IF NOT EXISTS user_scopes_user_id_idx ON user_scopes (user_id);
+";
+
+/// Companies and departments a user is bound to. Users without rows in
+/// `user_scopes` see everything.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RowScope {
+    pub companies: Vec<i64>,
+    pub departments: Vec<i64>,
+}
+
+/// Table and owner columns of the entities that belong to a company or a
+/// department. Certificates and educations belong to the owner of their
+/// contact.
+fn owner_columns(entity: &str) -> Option<(&'static str, &'static str, &'static str)> {
+    match entity {
+        "Certificate" => Some((
+            "certificates",
+            "(SELECT company_id FROM contacts WHERE contacts.id = certificates.contact_id)",
+            "(SELECT department_id FROM contacts WHERE contacts.id = certificates.contact_id)",
+        )),
+        "Company" => Some(("companies", "id", "NULL::bigint")),
+        "Contact" => Some(("contacts", "company_id", "department_id")),
+        "Education" => Some((
+            "educations",
+            "(SELECT company_id FROM contacts WHERE contacts.id = educations.contact_id)",
+            "(SELECT department_id FROM contacts WHERE contacts.id = educations.contact_id)",
+        )),
+        "Practice" => Some(("practices", "company_id", "NULL::bigint")),
+        "Siren" => Some(("sirens", "company_id", "NULL::bigint")),
+        _ => None,
+    }
+}
+
+fn is_contact_owned(entity: &str) -> bool {
+    matches!(entity, "Certificate" | "Education")
+}
+
+/// Whether rows of `entity` are hidden from users outside their owner.
+pub fn is_scoped(entity: &str) -> bool {
+    owner_columns(entity).is_some()
+}
+
+pub async fn init(client: &Client) -> Result<(), ServiceError> {
+    client.batch_execute(CREATE_TABLE).await?;
+    Ok(())
+}
+
+pub async fn load(client: &Client) -> Result<HashMap<i64, RowScope>, ServiceError> {
+    let rows = client
+        .query(
+            "SELECT user_id, company_id, department_id FROM user_scopes",
+            &[],
+        )
+        .await?;
+    let mut scopes: HashMap<i64, RowScope> = HashMap::new();
+    for row in rows {
+        let scope = scopes.entry(row.get(0)).or_default();
+        if let Some(company_id) = row.get::<_, Option<i64>>(1) {
+            scope.companies.push(company_id);
+        }
+        if let Some(department_id) = row.get::<_, Option<i64>>(2) {
+            scope.departments.push(department_id);
+        }
+    }
+    Ok(scopes)
+}
+
+impl RowScope {
+    fn contains(&self, company_id: Option<i64>, department_id: Option<i64>) -> bool {
+        company_id.map_or(false, |id| self.companies.contains(&id))
+            || department_id.map_or(false, |id| self.departments.contains(&id))
+    }
+
+    async fn ids(
+        &self,
+        entity: &str,
+        client: &Client,
+    ) -> Result<Option<HashSet<i64>>, ServiceError> {
+        let (table, company, department) = match owner_columns(entity) {
+            Some(columns) => columns,
+            None => return Ok(None),
+        };
+        let rows = client
+            .query(
+                format!(
+                    "SELECT id FROM {} WHERE {} = ANY($1) OR {} = ANY($2)",
+                    table, company, department
+                )
+                .as_str(),
+                &[&self.companies, &self.departments],
+            )
+            .await?;
+        Ok(Some(rows.iter().map(|row| row.get(0)).collect()))
+    }
+
+    async fn check_item(&self, entity: &str, id: i64, client: &Client) -> Result<(), ServiceError> {
+        let (table, company, department) = match owner_columns(entity) {
+            Some(columns) => columns,
+            None => return Ok(()),
+        };
+        let row = client
+            .query_opt(
+                format!(
+                    "SELECT {}, {} FROM {} WHERE id = $1",
+                    company, department, table
+                )
+                .as_str(),
+                &[&id],
+            )
+            .await?;
+        match row {
+            Some(row) if self.contains(row.get(0), row.get(1)) => Ok(()),
+            _ => Err(ServiceError::NotPermission),
+        }
+    }
+
+    /// Whether a change notice about `entity` `id` may reach the user. Rows
+    /// that cannot be looked up, such as purged ones, are not shown.
+    pub async fn sees(&self, entity: &str, id: i64, client: &Client) -> bool {
+        self.check_item(entity, id, client).await.is_ok()
+    }
+
+    async fn check_payload(&self, object: &DBObject, client: &Client) -> Result<(), ServiceError> {
+        let entity = object.name();
+        if owner_columns(&entity).is_none() {
+            return Ok(());
+        }
+        let value = serde_json::to_value(object)?;
+        let payload = &value[entity.as_str()];
+        let field = |name: &str| payload.get(name).and_then(Value::as_i64);
+        if is_contact_owned(&entity) {
+            return match field("contact_id") {
+                Some(contact_id) => self.check_item("Contact", contact_id, client).await,
+                None => Err(ServiceError::NotPermission),
+            };
+        }
+        let owner = if entity == "Company" {
+            (field("id"), None)
+        } else {
+            (field("company_id"), field("department_id"))
+        };
+        if self.contains(owner.0, owner.1) {
+            Ok(())
+        } else {
+            Err(ServiceError::NotPermission)
+        }
+    }
+}
+
+/// Rejects commands that read or write rows outside the user scope.
+pub async fn check(
+    scope: &Option<RowScope>,
+    command: &Command,
+    client: &Client,
+) -> Result<(), ServiceError> {
+    let scope = match scope {
+        Some(scope) => scope,
+        None => return Ok(()),
+    };
+    match command {
+        Command::Get(Object::Item(item))
+        | Command::History(item)
+        | Command::Delete(item)
+        | Command::Restore(item)
+        | Command::Purge(item) => scope.check_item(&item.name, item.id, client).await,
+        Command::Get(Object::ItemAt(item)) => scope.check_item(&item.name, item.id, client).await,
+        Command::Subscribe(Topic {
+            entity,
+            id: Some(id),
+        }) => scope.check_item(entity, *id, client).await,
+        Command::Insert(object) => scope.check_payload(object, client).await,
+        Command::Update(Versioned { object, .. }) => {
+            if let Some(id) = object.id() {
+                scope.check_item(&object.name(), id, client).await?;
+            }
+            scope.check_payload(object, client).await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Loads a list object keeping only the rows inside the user scope.
+pub async fn scoped_list(
+    scope: &Option<RowScope>,
+    name: &str,
+    client: &Client,
+) -> Result<DBObject, ServiceError> {
+    let object = get_list(name, client).await?;
+    let ids = match scope {
+        Some(scope) => match scope.ids(list_entity(name), client).await? {
+            Some(ids) => ids,
+            None => return Ok(object),
+        },
+        None => return Ok(object),
+    };
+    Ok(object.retain_ids(&ids))
+}
@@ -0,0 +1,173 @@
+use deadpool_postgres::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::dbo::DBObject;
+use crate::error::ServiceError;
+use crate::listing::contains_pattern;
+use crate::scoping::RowScope;
+
+const CREATE_EXTENSION: &str = "CREATE EXTENSION IF NOT EXISTS pg_trgm;";
+
+/// Trigram indexes on every column a search matches against.
+const CREATE_INDEXES: &str = "
+    CREATE INDEX IF NOT EXISTS contacts_name_trgm_idx ON contacts USING gin (name gin_trgm_ops);
+    CREATE INDEX IF NOT EXISTS companies_name_trgm_idx ON companies USING gin (name gin_trgm_ops);
+    CREATE INDEX IF NOT EXISTS companies_address_trgm_idx
+        ON companies USING gin (address gin_trgm_ops);
+    CREATE INDEX IF NOT EXISTS departments_name_trgm_idx
+        ON departments USING gin (name gin_trgm_ops);
+    CREATE INDEX IF NOT EXISTS emails_email_trgm_idx ON emails USING gin (email gin_trgm_ops);
+    CREATE INDEX IF NOT EXISTS phones_phone_trgm_idx
+        ON phones USING gin ((phone::text) gin_trgm_ops);
+    CREATE INDEX IF NOT EXISTS sirens_text_trgm_idx ON sirens USING gin (
+        (coalesce(num_id::text, '') || ' ' || coalesce(address, '') || ' ' || coalesce(desk, ''))
+        gin_trgm_ops
+    );
+";
+
+/// Rows are first picked through the trigram indexes, by a match in one of
+/// their own columns, their emails, phones or company. Only those rows get a
+/// searchable text, which ranks them. `$6` is the escaped `ILIKE` pattern of
+/// the text. Department rows are not scoped.
+const SEARCH: &str = "
+    WITH matched_companies AS (
+        SELECT id FROM companies
+        WHERE name ILIKE $6 OR name % $1 OR address ILIKE $6 OR address % $1
+    ),
+    matched_emails AS (
+        SELECT contact_id, company_id FROM emails WHERE email ILIKE $6 OR email % $1
+    ),
+    matched_phones AS (
+        SELECT contact_id, company_id FROM phones WHERE phone::text ILIKE $6
+    ),
+    contact_ids AS (
+        SELECT id FROM contacts WHERE name ILIKE $6 OR name % $1
+        UNION SELECT contact_id FROM matched_emails
+        UNION SELECT contact_id FROM matched_phones
+        UNION SELECT id FROM contacts WHERE company_id IN (SELECT id FROM matched_companies)
+    ),
+    company_ids AS (
+        SELECT id FROM matched_companies
+        UNION SELECT company_id FROM matched_emails
+        UNION SELECT company_id FROM matched_phones
+    ),
+    docs AS (
+        SELECT 'Contact' AS name, c.id, c.name AS title, c.company_id, c.department_id,
+            true AS scoped, concat_ws(' ', c.name, co.name,
+                (SELECT string_agg(email, ' ') FROM emails WHERE contact_id = c.id),
+                (SELECT string_agg(phone::text, ' ') FROM phones WHERE contact_id = c.id)
+            ) AS doc
+        FROM contacts c
+        LEFT JOIN companies co ON co.id = c.company_id
+        WHERE 'Contact' = ANY($2) AND c.deleted_at IS NULL
+            AND c.id IN (SELECT id FROM contact_ids)
+        UNION ALL
+        SELECT 'Company', co.id, co.name, co.id, NULL::bigint,
+            true, concat_ws(' ', co.name, co.address,
+                (SELECT string_agg(email, ' ') FROM emails WHERE company_id = co.id),
+                (SELECT string_agg(phone::text, ' ') FROM phones WHERE company_id = co.id)
+            )
+        FROM companies co
+        WHERE 'Company' = ANY($2) AND co.deleted_at IS NULL
+            AND co.id IN (SELECT id FROM company_ids)
+        UNION ALL
+        SELECT 'Department', d.id, d.name, NULL::bigint, NULL::bigint,
+            false, d.name
+        FROM departments d
+        WHERE 'Department' = ANY($2) AND d.deleted_at IS NULL
+            AND (d.name ILIKE $6 OR d.name % $1)
+        UNION ALL
+        SELECT 'Siren', s.id, concat_ws(' ', s.num_id, s.address), s.company_id, NULL::bigint,
+            true, concat_ws(' ', s.num_id, s.address, s.desk, co.name)
+        FROM sirens s
+        LEFT JOIN companies co ON co.id = s.company_id
+        WHERE 'Siren' = ANY($2) AND s.deleted_at IS NULL
+            AND ((coalesce(s.num_id::text, '') || ' ' || coalesce(s.address, '') || ' '
+                    || coalesce(s.desk, '')) ILIKE $6
+                OR (coalesce(s.num_id::text, '') || ' ' || coalesce(s.address, '') || ' '
+                    || coalesce(s.desk, '')) % $1
+                OR s.company_id IN (SELECT id FROM matched_companies))
+    )
+    SELECT name, id, COALESCE(title, ''),
+        GREATEST(
+            ts_rank(to_tsvector('simple', doc), plainto_tsquery('simple', $1)),
+            similarity(doc, $1)
+        )::real AS rank
+    FROM docs
+    WHERE NOT scoped OR $4::bigint[] IS NULL
+        OR company_id = ANY($4) OR department_id = ANY($5)
+    ORDER BY rank DESC, id
+    LIMIT $3
+";
+
+/// Entities covered by `Search`.
+pub const ENTITIES: [&str; 4] = ["Contact", "Company", "Department", "Siren"];
+
+const DEFAULT_LIMIT: i64 = 20;
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    pub text: String,
+    pub limit: Option<i64>,
+}
+
+/// A ranked match, pointing at the `Item` to load with `Get`.
+#[derive(Deserialize, Serialize)]
+pub struct SearchHit {
+    pub name: String,
+    pub id: i64,
+    pub title: String,
+    pub rank: f32,
+}
+
+/// Creates the trigram indexes. `pg_trgm` is only created when missing, as
+/// that needs rights the service user may lack.
+pub async fn init(client: &Client) -> Result<(), ServiceError> {
+    let installed = client
+        .query_opt("SELECT 1 FROM pg_extension WHERE extname = 'pg_trgm'", &[])
+        .await?
+        .is_some();
+    if !installed {
+        client.batch_execute(CREATE_EXTENSION).await?;
+    }
+    client.batch_execute(CREATE_INDEXES).await?;
+    Ok(())
+}
+
+/// Searches the given entities, keeping rows inside the user scope.
+pub async fn search(
+    query: &SearchQuery,
+    entities: &[&str],
+    scope: &Option<RowScope>,
+    client: &Client,
+) -> Result<DBObject, ServiceError> {
+    let text = query.text.trim();
+    if text.is_empty() {
+        return Err(ServiceError::BadRequest("empty search text".to_string()));
+    }
+    let companies = scope.as_ref().map(|scope| scope.companies.clone());
+    let departments = scope.as_ref().map(|scope| scope.departments.clone());
+    let rows = client
+        .query(
+            SEARCH,
+            &[
+                &text,
+                &entities,
+                &query.limit.unwrap_or(DEFAULT_LIMIT),
+                &companies,
+                &departments,
+                &contains_pattern(text),
+            ],
+        )
+        .await?;
+    Ok(DBObject::SearchHits(
+        rows.iter()
+            .map(|row| SearchHit {
+                name: row.get(0),
+                id: row.get(1),
+                title: row.get(2),
+                rank: row.get(3),
+            })
+            .collect(),
+    ))
+}
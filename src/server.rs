@@ -1,26 +1,73 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Duration;
 
-use actix::{Actor, Context, Handler, Message, Recipient, StreamHandler};
+use actix::{
+    fut, Actor, ActorFuture, AsyncContext, Context, ContextFutureSpawner, Handler, Message,
+    Recipient, StreamHandler, WrapFuture,
+};
 // use actix_web::{web, Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
-// use deadpool_postgres::Pool;
-// use log::info;
+use deadpool_postgres::Pool;
+use log::warn;
 use rand::{self, rngs::ThreadRng, Rng};
+use serde::{Deserialize, Serialize};
 // use serde_json::json;
 
+use rpel::get_pool;
+
 // use crate::db::WsMsg;
+use crate::db::{reload_users, UserData};
+use crate::dbo::list_entity;
 use crate::error::ServiceError;
+use crate::notify;
+use crate::scoping::RowScope;
+
+const USERS_RELOAD_INTERVAL: u64 = 60;
+const LISTEN_RETRY: Duration = Duration::from_secs(10);
 
-pub struct Msg(pub String);
+pub struct Msg {
+    pub session: usize,
+    pub user: UserData,
+    pub message: String,
+}
 
 impl Message for Msg {
     type Result = Result<String, ServiceError>;
 }
 
+/// Asks whether a change is inside the row scope of a session's user.
+pub struct Visible {
+    pub scope: RowScope,
+    pub change: Change,
+}
+
+impl Message for Visible {
+    type Result = bool;
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub enum Notice {
+    /// Digest of a revoked token.
+    Revoked(String),
+    Changed(Change),
+}
+
+/// A committed mutation, pushed to the other sessions subscribed to it so
+/// they can refresh.
+#[derive(Clone, Debug, Serialize)]
+pub struct Change {
+    pub entity: String,
+    pub id: i64,
+    pub command: String,
+}
+
 #[derive(Message)]
 #[rtype(usize)]
 pub struct Connect {
-    pub addr: Recipient<Msg>,
+    pub addr: Recipient<Notice>,
 }
 
 #[derive(Message)]
@@ -29,6 +76,24 @@ pub struct Disconnect {
     pub id: usize,
 }
 
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Revoke {
+    /// Digest of the token, see `token::digest`.
+    pub token: String,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ReloadUsers;
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Broadcast {
+    pub origin: usize,
+    pub change: Change,
+}
+
 // pub struct ClientMessage {
 //     pub id: usize,
 //     pub msg: String,
@@ -38,15 +103,66 @@ pub struct Disconnect {
 //     type Result = ();
 // }
 
-// #[derive(Message)]
-// #[rtype(result = "()")]
-// pub struct Join {
-//     pub id: usize,
-// }
+/// What a session listens to: every change of an entity (`SirenList` or
+/// `Siren`) or the changes of one item (`Contact:42`).
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Topic {
+    pub entity: String,
+    pub id: Option<i64>,
+}
+
+impl TryFrom<String> for Topic {
+    type Error = ServiceError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let bad = || ServiceError::BadRequest(format!("bad topic: {}", value));
+        let (entity, id) = match value.find(':') {
+            Some(pos) => (
+                &value[..pos],
+                Some(value[pos + 1..].parse().map_err(|_| bad())?),
+            ),
+            None => (list_entity(&value), None),
+        };
+        if entity.is_empty() {
+            return Err(bad());
+        }
+        Ok(Topic {
+            entity: entity.to_string(),
+            id,
+        })
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.id {
+            Some(id) => write!(f, "{}:{}", self.entity, id),
+            None => write!(f, "{}", self.entity),
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Join {
+    pub id: usize,
+    pub topic: Topic,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Leave {
+    pub id: usize,
+    pub topic: Topic,
+}
 
 pub struct Server {
-    sessions: HashMap<usize, Recipient<Msg>>,
+    sessions: HashMap<usize, Recipient<Notice>>,
+    topics: HashMap<Topic, HashSet<usize>>,
     rng: ThreadRng,
+    pool: Pool,
+    listener: Option<tokio_postgres::Client>,
     // db: Addr<DB>,
 }
 
@@ -54,7 +170,10 @@ impl Default for Server {
     fn default() -> Server {
         Server {
             sessions: HashMap::new(),
+            topics: HashMap::new(),
             rng: rand::thread_rng(),
+            pool: get_pool(),
+            listener: None,
             // db,
         }
     }
@@ -68,8 +187,89 @@ impl Default for Server {
 //     }
 // }
 
+impl Server {
+    fn revoked(&self, token: String) {
+        for addr in self.sessions.values() {
+            let _ = addr.do_send(Notice::Revoked(token.clone()));
+        }
+    }
+
+    /// Pushes a change to the sessions subscribed to it, except the session
+    /// that made it.
+    fn broadcast(&self, origin: Option<usize>, change: Change) {
+        let topics = [
+            Topic {
+                entity: change.entity.clone(),
+                id: None,
+            },
+            Topic {
+                entity: change.entity.clone(),
+                id: Some(change.id),
+            },
+        ];
+        let subscribers: HashSet<usize> = topics
+            .iter()
+            .filter_map(|topic| self.topics.get(topic))
+            .flatten()
+            .copied()
+            .filter(|id| Some(*id) != origin)
+            .collect();
+        for id in subscribers {
+            if let Some(addr) = self.sessions.get(&id) {
+                let _ = addr.do_send(Notice::Changed(change.clone()));
+            }
+        }
+    }
+
+    /// Subscribes to the changes announced by Postgres, retrying until the
+    /// listen connection is up.
+    fn listen(&mut self, ctx: &mut Context<Self>) {
+        notify::listen()
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok((client, changes)) => {
+                        act.listener = Some(client);
+                        ctx.add_stream(changes);
+                    }
+                    Err(err) => {
+                        warn!("listen failed: {}", err);
+                        ctx.run_later(LISTEN_RETRY, |act, ctx| act.listen(ctx));
+                    }
+                }
+                fut::ready(())
+            })
+            .spawn(ctx);
+    }
+}
+
 impl Actor for Server {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let secs = dotenv::var("USERS_RELOAD")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(USERS_RELOAD_INTERVAL);
+        ctx.run_interval(Duration::from_secs(secs), |_, ctx| ctx.notify(ReloadUsers));
+        self.listen(ctx);
+    }
+}
+
+/// Changes made by other processes or directly in the database.
+impl StreamHandler<Change> for Server {
+    fn handle(&mut self, change: Change, ctx: &mut Context<Self>) {
+        if change.entity == "User" {
+            ctx.notify(ReloadUsers);
+        }
+        self.broadcast(None, change);
+    }
+
+    fn finished(&mut self, ctx: &mut Context<Self>) {
+        warn!("listen connection lost");
+        self.listener = None;
+        ctx.run_later(LISTEN_RETRY, |act, ctx| act.listen(ctx));
+    }
 }
 
 impl Handler<Connect> for Server {
@@ -88,6 +288,65 @@ impl Handler<Disconnect> for Server {
 
     fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
         self.sessions.remove(&msg.id);
+        for subscribers in self.topics.values_mut() {
+            subscribers.remove(&msg.id);
+        }
+        self.topics.retain(|_, subscribers| !subscribers.is_empty());
+    }
+}
+
+impl Handler<Join> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: Join, _: &mut Context<Self>) {
+        self.topics.entry(msg.topic).or_default().insert(msg.id);
+    }
+}
+
+impl Handler<Leave> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: Leave, _: &mut Context<Self>) {
+        if let Some(subscribers) = self.topics.get_mut(&msg.topic) {
+            subscribers.remove(&msg.id);
+            if subscribers.is_empty() {
+                self.topics.remove(&msg.topic);
+            }
+        }
+    }
+}
+
+impl Handler<Revoke> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: Revoke, _: &mut Context<Self>) {
+        self.revoked(msg.token);
+    }
+}
+
+impl Handler<Broadcast> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: Broadcast, _: &mut Context<Self>) {
+        self.broadcast(Some(msg.origin), msg.change);
+    }
+}
+
+impl Handler<ReloadUsers> for Server {
+    type Result = ();
+
+    fn handle(&mut self, _: ReloadUsers, ctx: &mut Context<Self>) {
+        let pool = self.pool.clone();
+        async move { reload_users(&pool).await }
+            .into_actor(self)
+            .then(|res, act, _| {
+                match res {
+                    Ok(keys) => keys.into_iter().for_each(|key| act.revoked(key)),
+                    Err(err) => warn!("reload users failed: {}", err),
+                }
+                fut::ready(())
+            })
+            .spawn(ctx);
     }
 }
 struct MyWs {
@@ -108,3 +367,22 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWs {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topic(name: &str) -> Topic {
+        Topic::try_from(name.to_string()).unwrap()
+    }
+
+    #[test]
+    fn topics_name_entities_or_items() {
+        assert_eq!(topic("SirenList"), topic("Siren"));
+        assert_eq!(topic("Contact:42").entity, "Contact");
+        assert_eq!(topic("Contact:42").id, Some(42));
+        assert_eq!(topic("Contact:42").to_string(), "Contact:42");
+        assert!(Topic::try_from("Contact:x".to_string()).is_err());
+        assert!(Topic::try_from(":1".to_string()).is_err());
+    }
+}
@@ -4,27 +4,58 @@ use actix::{
     fut, Actor, ActorContext, ActorFuture, Addr, AsyncContext, ContextFutureSpawner, Handler,
     Running, StreamHandler, WrapFuture,
 };
-use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web::{http::header, web, Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
 // use log::info;
+use serde::Deserialize;
+use serde_json::json;
 
-use crate::db::DB;
+use crate::db::{get_session, UserData, WsErrorMsg, DB};
 use crate::error::ServiceError;
-use crate::server::{Connect, Disconnect, Msg, Server};
+use crate::roles::{self, Action};
+use crate::scoping;
+use crate::server::{Change, Connect, Disconnect, Msg, Notice, Server, Visible};
+use crate::token::Token;
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct Hello {
+    hello: String,
+}
+
+/// Token of the `Authorization` header. Clients that cannot set it, such as
+/// browsers, send a `hello` frame instead; a query parameter would end up in
+/// the access log.
+fn handshake_token(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.to_string())
+}
 
 pub async fn wsroute(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Addr<Server>>,
 ) -> Result<HttpResponse, Error> {
+    let (token, user) = match handshake_token(&req) {
+        Some(key) => {
+            let (token, user) = get_session(&key).await?.ok_or(ServiceError::NotAuth)?;
+            (Some(token), Some(user))
+        }
+        None => (None, None),
+    };
     let db = DB::new(srv.get_ref().clone()).start();
     ws::start(
         Session {
             id: 0,
             hb: Instant::now(),
+            token,
+            user,
             server: srv.get_ref().clone(),
             db,
         },
@@ -33,9 +64,15 @@ pub async fn wsroute(
     )
 }
 
+fn changed(change: &Change) -> String {
+    json!({"command": "Changed", "object": change}).to_string()
+}
+
 struct Session {
     id: usize,
     hb: Instant,
+    token: Option<Token>,
+    user: Option<UserData>,
     server: Addr<Server>,
     db: Addr<DB>,
 }
@@ -46,6 +83,12 @@ impl Actor for Session {
     fn started(&mut self, ctx: &mut Self::Context) {
         self.hb(ctx);
 
+        ctx.run_later(AUTH_TIMEOUT, |act, ctx| {
+            if act.user.is_none() {
+                act.close(ctx, "not authenticated");
+            }
+        });
+
         let addr = ctx.address();
         self.server
             .send(Connect {
@@ -68,11 +111,51 @@ impl Actor for Session {
     }
 }
 
-impl Handler<Msg> for Session {
-    type Result = Result<String, ServiceError>;
+impl Handler<Notice> for Session {
+    type Result = ();
 
-    fn handle(&mut self, msg: Msg, _ctx: &mut Self::Context) -> Self::Result {
-        Ok(msg.0)
+    fn handle(&mut self, msg: Notice, ctx: &mut Self::Context) {
+        match msg {
+            Notice::Revoked(token) => {
+                if self
+                    .token
+                    .as_ref()
+                    .map_or(false, |own| own.digest() == token)
+                {
+                    self.close(ctx, "token revoked");
+                }
+            }
+            Notice::Changed(change) => {
+                let user = match &self.user {
+                    Some(user)
+                        if roles::allows(&user.permissions, Action::Read, &change.entity) =>
+                    {
+                        user
+                    }
+                    _ => return,
+                };
+                let scope = match &user.scope {
+                    Some(scope) if scoping::is_scoped(&change.entity) => scope.clone(),
+                    _ => {
+                        ctx.text(changed(&change));
+                        return;
+                    }
+                };
+                self.db
+                    .send(Visible {
+                        scope,
+                        change: change.clone(),
+                    })
+                    .into_actor(self)
+                    .then(move |res, _, ctx| {
+                        if let Ok(true) = res {
+                            ctx.text(changed(&change));
+                        }
+                        fut::ready(())
+                    })
+                    .spawn(ctx);
+            }
+        }
     }
 }
 
@@ -94,22 +177,43 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Session {
             ws::Message::Pong(_) => {
                 self.hb = Instant::now();
             }
-            ws::Message::Text(msg) => self
-                .db
-                .send(Msg(msg))
-                .into_actor(self)
-                .then(|res, _self_actor, ctx| {
-                    match res {
-                        Ok(res_wsmsg) => match res_wsmsg {
-                            Ok(txt) => ctx.text(txt),
-                            Err(err) => println!("err wsmsg: {:?}", err.to_string()),
-                        },
-                        _ => println!("Something is wrong"),
+            ws::Message::Text(msg) => {
+                if let Ok(hello) = serde_json::from_str::<Hello>(&msg) {
+                    self.authenticate(hello.hello, ctx);
+                    return;
+                }
+                let user = match &self.user {
+                    Some(user) => user.clone(),
+                    None => {
+                        self.close(ctx, "not authenticated");
+                        return;
                     }
-                    fut::ready(())
-                })
-                .wait(ctx),
-            ws::Message::Binary(_) => println!("Unexpected binary"),
+                };
+                self.db
+                    .send(Msg {
+                        session: self.id,
+                        user,
+                        message: msg,
+                    })
+                    .into_actor(self)
+                    .then(|res, _self_actor, ctx| {
+                        match res {
+                            Ok(res_wsmsg) => match res_wsmsg {
+                                Ok(txt) => ctx.text(txt),
+                                Err(err) => ctx.text(WsErrorMsg::text(None, &err)),
+                            },
+                            _ => {
+                                ctx.text(WsErrorMsg::text(None, &ServiceError::InternalServerError))
+                            }
+                        }
+                        fut::ready(())
+                    })
+                    .spawn(ctx)
+            }
+            ws::Message::Binary(_) => ctx.text(WsErrorMsg::text(
+                None,
+                &ServiceError::BadRequest("unexpected binary".to_string()),
+            )),
             ws::Message::Close(reason) => {
                 ctx.close(reason);
                 ctx.stop();
@@ -131,7 +235,39 @@ impl Session {
                 return;
             }
 
+            if act.token.as_ref().map_or(false, Token::is_expired) {
+                act.close(ctx, "token expired");
+                return;
+            }
+
             ctx.ping(b"");
         });
     }
+
+    /// Binds the session to the user owning `key`. A hello frame may be sent
+    /// again after a token refresh to rebind the session to the new token.
+    fn authenticate(&mut self, key: String, ctx: &mut ws::WebsocketContext<Self>) {
+        async move { get_session(&key).await }
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Some((token, user))) => {
+                        ctx.text(json!({"command": "Hello", "role": user.role}).to_string());
+                        act.token = Some(token);
+                        act.user = Some(user);
+                    }
+                    _ => act.close(ctx, "not authenticated"),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn close(&self, ctx: &mut ws::WebsocketContext<Self>, description: &str) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(description.to_string()),
+        }));
+        ctx.stop();
+    }
 }
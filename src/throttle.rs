@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::error::ServiceError;
+
+const FREE_ATTEMPTS: u32 = 5;
+/// An address may be shared by every client behind a proxy, so it takes many
+/// more failures to lock it than a username.
+const ADDRESS_FREE_ATTEMPTS: u32 = 50;
+const ADDRESS: &str = "ip:";
+const BASE_LOCKOUT: u64 = 1;
+const MAX_LOCKOUT: u64 = 15 * 60;
+
+struct Attempts {
+    failures: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+static ATTEMPTS: Lazy<Mutex<HashMap<String, Attempts>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Failed logins are counted per client address and per username; both keys
+/// are checked so that neither rotating addresses nor usernames bypasses the
+/// lockout.
+pub fn keys(ip: &str, username: &str) -> [String; 2] {
+    [format!("{}{}", ADDRESS, ip), user_key(username)]
+}
+
+fn free_attempts(key: &str) -> u32 {
+    if key.starts_with(ADDRESS) {
+        ADDRESS_FREE_ATTEMPTS
+    } else {
+        FREE_ATTEMPTS
+    }
+}
+
+fn user_key(username: &str) -> String {
+    format!("user:{}", username)
+}
+
+fn lockout(failures: u32, free: u32) -> Duration {
+    let exp = failures.saturating_sub(free).min(16);
+    Duration::from_secs((BASE_LOCKOUT << exp).min(MAX_LOCKOUT))
+}
+
+pub fn check(keys: &[String]) -> Result<(), ServiceError> {
+    let attempts = ATTEMPTS
+        .lock()
+        .map_err(|_| ServiceError::InternalServerError)?;
+    let now = Instant::now();
+    let wait = keys
+        .iter()
+        .filter_map(|key| attempts.get(key)?.locked_until)
+        .filter(|until| *until > now)
+        .map(|until| until - now)
+        .max();
+    match wait {
+        Some(wait) => Err(ServiceError::TooManyRequests(wait.as_secs() + 1)),
+        None => Ok(()),
+    }
+}
+
+pub fn failure(keys: &[String]) {
+    if let Ok(mut attempts) = ATTEMPTS.lock() {
+        let now = Instant::now();
+        let max_lockout = Duration::from_secs(MAX_LOCKOUT);
+        attempts.retain(|_, entry| now.duration_since(entry.last) < max_lockout);
+        for key in keys {
+            let entry = attempts.entry(key.clone()).or_insert(Attempts {
+                failures: 0,
+                last: now,
+                locked_until: None,
+            });
+            entry.failures += 1;
+            entry.last = now;
+            let free = free_attempts(key);
+            if entry.failures >= free {
+                entry.locked_until = Some(now + lockout(entry.failures, free));
+            }
+        }
+    }
+}
+
+pub fn success(username: &str) {
+    if let Ok(mut attempts) = ATTEMPTS.lock() {
+        attempts.remove(&user_key(username));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockout_doubles_up_to_the_cap() {
+        assert_eq!(
+            lockout(FREE_ATTEMPTS, FREE_ATTEMPTS),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            lockout(FREE_ATTEMPTS + 1, FREE_ATTEMPTS),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            lockout(FREE_ATTEMPTS + 3, FREE_ATTEMPTS),
+            Duration::from_secs(8)
+        );
+        assert_eq!(
+            lockout(100, FREE_ATTEMPTS),
+            Duration::from_secs(MAX_LOCKOUT)
+        );
+    }
+
+    #[test]
+    fn failures_lock_the_username() {
+        let keys = keys("192.0.2.1", "throttled");
+        for _ in 1..FREE_ATTEMPTS {
+            failure(&keys);
+        }
+        assert!(check(&keys).is_ok());
+        failure(&keys);
+        assert!(check(&keys).is_err());
+        assert!(check(&super::keys("192.0.2.2", "throttled")).is_err());
+        assert!(check(&super::keys("192.0.2.1", "other")).is_ok());
+        success("throttled");
+        assert!(check(&keys).is_ok());
+    }
+
+    #[test]
+    fn failures_across_usernames_lock_the_address() {
+        for n in 1..ADDRESS_FREE_ATTEMPTS {
+            failure(&keys("192.0.2.3", &format!("sprayed{}", n)));
+        }
+        assert!(check(&keys("192.0.2.3", "fresh")).is_ok());
+        failure(&keys("192.0.2.3", "sprayed"));
+        assert!(check(&keys("192.0.2.3", "fresh")).is_err());
+        assert!(check(&keys("192.0.2.4", "fresh")).is_ok());
+    }
+}
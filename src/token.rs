@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::iter;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use deadpool_postgres::Pool;
+use log::warn;
+use once_cell::sync::OnceCell;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sha2::{Digest, Sha256};
+
+use crate::error::ServiceError;
+
+const KEY_LEN: usize = 20;
+const ACCESS_TTL: u64 = 12 * 60 * 60;
+const REFRESH_TTL: u64 = 7 * 24 * 60 * 60;
+
+/// Tokens are stored by the SHA-256 digest of their key, so a copy of the
+/// table does not hand out sessions.
+const CREATE_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS tokens (
+        key TEXT PRIMARY KEY,
+        user_id BIGINT NOT NULL,
+        issued_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+    CREATE INDEX IF NOT EXISTS tokens_user_id_idx ON tokens (user_id);
+";
+
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub key: String,
+    pub user_id: i64,
+    pub issued_at: SystemTime,
+}
+
+#[derive(Clone, Copy)]
+struct Lifetime {
+    access: Duration,
+    refresh: Duration,
+}
+
+impl Token {
+    fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.issued_at)
+            .unwrap_or_default()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.age() > lifetime().access
+    }
+
+    pub fn is_refreshable(&self) -> bool {
+        self.age() <= lifetime().refresh
+    }
+
+    pub fn digest(&self) -> String {
+        digest(&self.key)
+    }
+}
+
+/// What the store keeps of a token; the key itself is never stored.
+#[derive(Clone)]
+struct Record {
+    user_id: i64,
+    issued_at: SystemTime,
+}
+
+impl Record {
+    fn token(self, key: &str) -> Token {
+        Token {
+            key: key.to_string(),
+            user_id: self.user_id,
+            issued_at: self.issued_at,
+        }
+    }
+}
+
+pub enum TokenStore {
+    Postgres(Pool),
+    Memory(Mutex<HashMap<String, Record>>),
+}
+
+static TOKENS: OnceCell<TokenStore> = OnceCell::new();
+static LIFETIME: OnceCell<Lifetime> = OnceCell::new();
+
+impl TokenStore {
+    fn memory() -> TokenStore {
+        TokenStore::Memory(Mutex::new(HashMap::new()))
+    }
+
+    async fn postgres(pool: Pool) -> Result<TokenStore, ServiceError> {
+        let client = pool.get().await?;
+        client.batch_execute(CREATE_TABLE).await?;
+        Ok(TokenStore::Postgres(pool))
+    }
+
+    async fn insert(&self, token: &Token) -> Result<(), ServiceError> {
+        match self {
+            TokenStore::Postgres(pool) => {
+                let client = pool.get().await?;
+                client
+                    .execute(
+                        "INSERT INTO tokens (key, user_id, issued_at) VALUES ($1, $2, $3)",
+                        &[&token.digest(), &token.user_id, &token.issued_at],
+                    )
+                    .await?;
+            }
+            TokenStore::Memory(tokens) => {
+                lock(tokens)?.insert(
+                    token.digest(),
+                    Record {
+                        user_id: token.user_id,
+                        issued_at: token.issued_at,
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Token>, ServiceError> {
+        match self {
+            TokenStore::Postgres(pool) => {
+                let client = pool.get().await?;
+                let row = client
+                    .query_opt(
+                        "SELECT user_id, issued_at FROM tokens WHERE key = $1",
+                        &[&digest(key)],
+                    )
+                    .await?;
+                Ok(row.map(|row| Token {
+                    key: key.to_string(),
+                    user_id: row.get(0),
+                    issued_at: row.get(1),
+                }))
+            }
+            TokenStore::Memory(tokens) => Ok(lock(tokens)?
+                .get(&digest(key))
+                .cloned()
+                .map(|record| record.token(key))),
+        }
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), ServiceError> {
+        match self {
+            TokenStore::Postgres(pool) => {
+                let client = pool.get().await?;
+                client
+                    .execute("DELETE FROM tokens WHERE key = $1", &[&digest(key)])
+                    .await?;
+            }
+            TokenStore::Memory(tokens) => {
+                lock(tokens)?.remove(&digest(key));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes every token of a user and returns their digests.
+    async fn remove_user(&self, user_id: i64) -> Result<Vec<String>, ServiceError> {
+        match self {
+            TokenStore::Postgres(pool) => {
+                let client = pool.get().await?;
+                let rows = client
+                    .query(
+                        "DELETE FROM tokens WHERE user_id = $1 RETURNING key",
+                        &[&user_id],
+                    )
+                    .await?;
+                Ok(rows.iter().map(|row| row.get(0)).collect())
+            }
+            TokenStore::Memory(tokens) => {
+                let mut tokens = lock(tokens)?;
+                let keys: Vec<String> = tokens
+                    .iter()
+                    .filter(|(_, record)| record.user_id == user_id)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in &keys {
+                    tokens.remove(key);
+                }
+                Ok(keys)
+            }
+        }
+    }
+
+    async fn prune(&self) -> Result<(), ServiceError> {
+        match self {
+            TokenStore::Postgres(pool) => {
+                let client = pool.get().await?;
+                let oldest = SystemTime::now() - lifetime().refresh;
+                client
+                    .execute("DELETE FROM tokens WHERE issued_at < $1", &[&oldest])
+                    .await?;
+            }
+            TokenStore::Memory(tokens) => {
+                lock(tokens)?.retain(|key, record| record.clone().token(key).is_refreshable());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<std::sync::MutexGuard<'_, T>, ServiceError> {
+    mutex.lock().map_err(|_| ServiceError::InternalServerError)
+}
+
+fn store() -> Result<&'static TokenStore, ServiceError> {
+    TOKENS.get().ok_or(ServiceError::InternalServerError)
+}
+
+fn lifetime() -> Lifetime {
+    LIFETIME.get().copied().unwrap_or(Lifetime {
+        access: Duration::from_secs(ACCESS_TTL),
+        refresh: Duration::from_secs(REFRESH_TTL),
+    })
+}
+
+fn env_secs(name: &str, default: u64) -> Duration {
+    let secs = dotenv::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default);
+    Duration::from_secs(secs)
+}
+
+/// Hex SHA-256 of a token key, the form tokens are stored and revoked by.
+pub fn digest(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn new_key() -> String {
+    let mut rng = thread_rng();
+    iter::repeat(())
+        .map(|()| rng.sample(Alphanumeric))
+        .take(KEY_LEN)
+        .collect()
+}
+
+pub async fn init(pool: Pool) -> Result<(), ServiceError> {
+    let access = env_secs("TOKEN_TTL", ACCESS_TTL);
+    let refresh = env_secs("REFRESH_TTL", REFRESH_TTL).max(access);
+    let _result = LIFETIME.set(Lifetime { access, refresh });
+    let store = if dotenv::var("TOKEN_STORE").unwrap_or_default() == "memory" {
+        TokenStore::memory()
+    } else {
+        match TokenStore::postgres(pool).await {
+            Ok(store) => store,
+            Err(err) => {
+                warn!("tokens table unavailable, using in-memory store: {}", err);
+                TokenStore::memory()
+            }
+        }
+    };
+    store.prune().await?;
+    let _result = TOKENS.set(store);
+    Ok(())
+}
+
+pub async fn issue(user_id: i64) -> Result<String, ServiceError> {
+    let token = Token {
+        key: new_key(),
+        user_id,
+        issued_at: SystemTime::now(),
+    };
+    let store = store()?;
+    store.prune().await?;
+    store.insert(&token).await?;
+    Ok(token.key)
+}
+
+pub async fn lookup(key: &str) -> Result<Option<Token>, ServiceError> {
+    store()?.get(key).await
+}
+
+pub async fn refresh(key: &str) -> Result<Option<Token>, ServiceError> {
+    let old = match lookup(key).await? {
+        Some(token) if token.is_refreshable() => token,
+        _ => return Ok(None),
+    };
+    let new_key = issue(old.user_id).await?;
+    revoke(key).await?;
+    lookup(&new_key).await
+}
+
+pub async fn revoke(key: &str) -> Result<(), ServiceError> {
+    store()?.remove(key).await
+}
+
+pub async fn revoke_user(user_id: i64) -> Result<Vec<String>, ServiceError> {
+    store()?.remove_user(user_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_stored_by_digest() {
+        assert_eq!(
+            digest("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let token = Token {
+            key: new_key(),
+            user_id: 1,
+            issued_at: SystemTime::now(),
+        };
+        assert_eq!(token.digest().len(), 64);
+        assert_ne!(token.digest(), token.key);
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::db::Item;
+use crate::dbo::{list_entity, DBObject};
+use crate::error::ServiceError;
+use crate::registry::{self, ENTITIES};
+
+#[derive(Deserialize, Serialize)]
+pub struct TrashEntry {
+    pub name: String,
+    pub id: i64,
+    pub deleted_at: DateTime<Utc>,
+    pub deleted_by: Option<i64>,
+}
+
+/// Table of an entity deleted into the trash.
+pub fn soft_table(entity: &str) -> Option<&'static str> {
+    registry::entity(entity)
+        .filter(|entity| entity.soft_delete)
+        .map(|entity| entity.table)
+}
+
+/// Adds the trash columns to the tables that lack them. Tables that have them
+/// are not altered, so a restart takes no table locks.
+pub async fn init(client: &Client) -> Result<(), ServiceError> {
+    let rows = client
+        .query(
+            "SELECT table_name::text FROM information_schema.columns
+            WHERE table_schema = current_schema() AND column_name = 'deleted_by'",
+            &[],
+        )
+        .await?;
+    let altered: HashSet<String> = rows.iter().map(|row| row.get(0)).collect();
+    for entity in ENTITIES.iter() {
+        if entity.soft_delete && !altered.contains(entity.table) {
+            client
+                .batch_execute(
+                    format!(
+                        "ALTER TABLE {} ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ,
+                        ADD COLUMN IF NOT EXISTS deleted_by BIGINT;",
+                        entity.table
+                    )
+                    .as_str(),
+                )
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+fn bad_item(item: &Item) -> ServiceError {
+    ServiceError::BadRequest(format!("bad trash item {} {}", item.name, item.id))
+}
+
+pub async fn discard(item: &Item, user_id: i64, client: &Client) -> Result<i64, ServiceError> {
+    let table = soft_table(&item.name).ok_or_else(|| bad_item(item))?;
+    let rows = client
+        .execute(
+            format!(
+                "UPDATE {} SET deleted_at = now(), deleted_by = $2
+                WHERE id = $1 AND deleted_at IS NULL",
+                table
+            )
+            .as_str(),
+            &[&item.id, &user_id],
+        )
+        .await?;
+    if rows == 0 {
+        return Err(bad_item(item));
+    }
+    Ok(rows as i64)
+}
+
+pub async fn restore(item: &Item, client: &Client) -> Result<i64, ServiceError> {
+    let table = soft_table(&item.name).ok_or_else(|| bad_item(item))?;
+    let rows = client
+        .execute(
+            format!(
+                "UPDATE {} SET deleted_at = NULL, deleted_by = NULL
+                WHERE id = $1 AND deleted_at IS NOT NULL",
+                table
+            )
+            .as_str(),
+            &[&item.id],
+        )
+        .await?;
+    if rows == 0 {
+        return Err(bad_item(item));
+    }
+    Ok(rows as i64)
+}
+
+/// Fails unless the item is in the trash, so only trashed items are purged.
+pub async fn check_discarded(item: &Item, client: &Client) -> Result<(), ServiceError> {
+    let table = soft_table(&item.name).ok_or_else(|| bad_item(item))?;
+    let row = client
+        .query_opt(
+            format!(
+                "SELECT id FROM {} WHERE id = $1 AND deleted_at IS NOT NULL",
+                table
+            )
+            .as_str(),
+            &[&item.id],
+        )
+        .await?;
+    row.map(|_| ()).ok_or_else(|| bad_item(item))
+}
+
+/// Drops trashed rows from a list or select object.
+pub async fn hide_deleted(
+    name: &str,
+    object: DBObject,
+    client: &Client,
+) -> Result<DBObject, ServiceError> {
+    let table = match soft_table(list_entity(name)) {
+        Some(table) => table,
+        None => return Ok(object),
+    };
+    let rows = client
+        .query(
+            format!("SELECT id FROM {} WHERE deleted_at IS NOT NULL", table).as_str(),
+            &[],
+        )
+        .await?;
+    if rows.is_empty() {
+        return Ok(object);
+    }
+    let deleted: HashSet<i64> = rows.iter().map(|row| row.get(0)).collect();
+    let key = object.name();
+    let mut value = serde_json::to_value(object)?;
+    if let Some(list) = value.get_mut(key.as_str()).and_then(Value::as_array_mut) {
+        list.retain(|row| {
+            row.get("id")
+                .and_then(Value::as_i64)
+                .map_or(true, |id| !deleted.contains(&id))
+        });
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+pub async fn get_list(client: &Client) -> Result<DBObject, ServiceError> {
+    let query = ENTITIES
+        .iter()
+        .filter(|entity| entity.soft_delete)
+        .map(|entity| {
+            format!(
+                "SELECT '{}' AS name, id, deleted_at, deleted_by FROM {}
+                WHERE deleted_at IS NOT NULL",
+                entity.name, entity.table
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(" UNION ALL ");
+    let rows = client
+        .query(format!("{} ORDER BY deleted_at DESC", query).as_str(), &[])
+        .await?;
+    Ok(DBObject::TrashList(
+        rows.iter()
+            .map(|row| TrashEntry {
+                name: row.get(0),
+                id: row.get(1),
+                deleted_at: row.get(2),
+                deleted_by: row.get(3),
+            })
+            .collect(),
+    ))
+}
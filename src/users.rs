@@ -1,19 +1,32 @@
 use deadpool_postgres::Client;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use rpel::user::{User, UserList};
 
+use crate::audit;
+use crate::db::Item;
+use crate::dbo::{version, DBObject};
 use crate::error::ServiceError;
+use crate::password;
 
 #[derive(Serialize, Deserialize)]
 pub enum UserObject {
     Get(i64),
     GetList,
     Insert(User),
-    Update(User),
+    Update(VersionedUser),
     Delete(i64),
 }
 
+/// A user to update and the version it was read at, checked like the
+/// version of `Update`.
+#[derive(Serialize, Deserialize)]
+pub struct VersionedUser {
+    pub object: User,
+    pub version: i64,
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum DBUserObject {
     Null,
@@ -24,60 +37,128 @@ pub enum DBUserObject {
 
 #[derive(Serialize, Deserialize)]
 pub struct WsUserMsg {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
     pub command: String,
     pub object: DBUserObject,
+    /// Row version to send back with the next `Update` of the user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
     pub error: String,
 }
 
 impl WsUserMsg {
     fn from_get(object: User) -> Self {
         WsUserMsg {
+            id: None,
             command: "Get".to_string(),
-            object: DBUserObject::User(object),
+            object: DBUserObject::User(hide_key(object)),
+            version: None,
             error: String::new(),
         }
     }
 
     fn from_list(object: Vec<UserList>) -> Self {
         WsUserMsg {
+            id: None,
             command: "GetList".to_string(),
-            object: DBUserObject::UserList(object),
+            object: DBUserObject::UserList(hide_keys(object)),
+            version: None,
             error: String::new(),
         }
     }
 
-    fn from_insert(object: User) -> Self {
+    fn from_insert(object: i64) -> Self {
         WsUserMsg {
+            id: None,
             command: "Insert".to_string(),
-            object: DBUserObject::ID(object.id),
+            object: DBUserObject::ID(object),
+            version: None,
             error: String::new(),
         }
     }
 
-    fn from_update(object: u64) -> Self {
+    fn from_update(object: i64) -> Self {
         WsUserMsg {
+            id: None,
             command: "Update".to_string(),
-            object: DBUserObject::ID(object as i64),
+            object: DBUserObject::ID(object),
+            version: None,
             error: String::new(),
         }
     }
 
-    fn from_delete(object: u64) -> Self {
+    fn from_delete(object: i64) -> Self {
         WsUserMsg {
+            id: None,
             command: "Delete".to_string(),
-            object: DBUserObject::ID(object as i64),
+            object: DBUserObject::ID(object),
+            version: None,
             error: String::new(),
         }
     }
 }
 
-pub async fn user_cmd(obj: UserObject, client: &Client) -> Result<String, ServiceError> {
+/// Replaces a plain key with its hash before the user is stored. An empty key
+/// on an existing user keeps the stored hash.
+pub async fn hash_key(mut item: User, client: &Client) -> Result<User, ServiceError> {
+    if item.key.is_empty() {
+        if item.id == 0 {
+            return Err(ServiceError::BadRequest("empty user key".to_string()));
+        }
+        item.key = User::get(&client, item.id).await?.key;
+    } else if !password::is_hashed(&item.key) {
+        item.key = password::hash(&item.key).await?;
+    }
+    Ok(item)
+}
+
+pub fn hide_key(mut item: User) -> User {
+    item.key = String::new();
+    item
+}
+
+pub fn hide_keys(items: Vec<UserList>) -> Vec<UserList> {
+    items
+        .into_iter()
+        .map(|mut item| {
+            item.key = String::new();
+            item
+        })
+        .collect()
+}
+
+pub async fn user_cmd(
+    user_id: i64,
+    obj: UserObject,
+    client: &Client,
+) -> Result<WsUserMsg, ServiceError> {
     let a = match obj {
-        UserObject::Get(id) => WsUserMsg::from_get(User::get(&client, id).await?),
+        UserObject::Get(id) => {
+            let mut msg = WsUserMsg::from_get(User::get(&client, id).await?);
+            msg.version = version("User", id, false, client).await?;
+            msg
+        }
         UserObject::GetList => WsUserMsg::from_list(UserList::get_all(&client).await?),
-        UserObject::Insert(item) => WsUserMsg::from_insert(User::insert(&client, item).await?),
-        UserObject::Update(item) => WsUserMsg::from_update(User::update(&client, item).await?),
-        UserObject::Delete(id) => WsUserMsg::from_delete(User::delete(&client, id).await?),
+        UserObject::Insert(item) => {
+            WsUserMsg::from_insert(audit::insert(user_id, DBObject::User(item), client).await?)
+        }
+        UserObject::Update(update) => {
+            let id = update.object.id;
+            let object = DBObject::User(update.object);
+            let mut msg = WsUserMsg::from_update(
+                audit::update(user_id, object, update.version, client).await?,
+            );
+            msg.version = version("User", id, false, client).await?;
+            msg
+        }
+        UserObject::Delete(id) => {
+            let item = Item {
+                name: "User".to_string(),
+                id,
+            };
+            WsUserMsg::from_delete(audit::delete(user_id, &item, client).await?)
+        }
     };
-    Ok(serde_json::to_string(&a)?)
+    Ok(a)
 }
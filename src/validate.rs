@@ -0,0 +1,377 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use rpel::certificate::Certificate;
+use rpel::company::Company;
+use rpel::contact::Contact;
+use rpel::department::Department;
+use rpel::education::Education;
+use rpel::kind::Kind;
+use rpel::post::Post;
+use rpel::practice::Practice;
+use rpel::rank::Rank;
+use rpel::scope::Scope;
+use rpel::siren::Siren;
+use rpel::siren_type::SirenType;
+use rpel::user::User;
+
+use crate::dbo::DBObject;
+use crate::error::ServiceError;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub error: String,
+}
+
+/// Checks of an item payload before it is written.
+pub trait Validate {
+    fn validate(&self) -> Vec<FieldError>;
+}
+
+/// Rules over the serialized fields of a payload. Fields missing from the
+/// payload are skipped.
+struct Fields {
+    value: Value,
+    errors: Vec<FieldError>,
+}
+
+impl Fields {
+    fn of<T: Serialize>(item: &T) -> Fields {
+        Fields::from_value(serde_json::to_value(item).unwrap_or_default())
+    }
+
+    fn from_value(value: Value) -> Fields {
+        Fields {
+            value,
+            errors: Vec::new(),
+        }
+    }
+
+    fn error(&mut self, field: &str, error: &str) {
+        self.errors.push(FieldError {
+            field: field.to_string(),
+            error: error.to_string(),
+        });
+    }
+
+    fn required(mut self, field: &str) -> Fields {
+        let empty = match self.value.get(field) {
+            Some(Value::String(text)) => text.trim().is_empty(),
+            Some(Value::Null) => true,
+            _ => false,
+        };
+        if empty {
+            self.error(field, "required");
+        }
+        self
+    }
+
+    /// Rejects negative `id` and `*_id` fields.
+    fn ids(mut self) -> Fields {
+        let negative: Vec<String> = self
+            .value
+            .as_object()
+            .map(|fields| {
+                fields
+                    .iter()
+                    .filter(|(key, _)| *key == "id" || key.ends_with("_id"))
+                    .filter(|(_, value)| value.as_i64().map_or(false, |id| id < 0))
+                    .map(|(key, _)| key.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        for field in negative {
+            self.error(&field, "must not be negative");
+        }
+        self
+    }
+
+    fn strings(&self, field: &str) -> Vec<String> {
+        match self.value.get(field) {
+            Some(Value::String(text)) => vec![text.clone()],
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|item| match item {
+                    Value::String(text) => text.clone(),
+                    other => other.to_string(),
+                })
+                .collect(),
+            Some(Value::Number(number)) => vec![number.to_string()],
+            _ => Vec::new(),
+        }
+    }
+
+    fn emails(mut self, field: &str) -> Fields {
+        let bad = self.strings(field).iter().any(|email| !is_email(email));
+        if bad {
+            self.error(field, "malformed email");
+        }
+        self
+    }
+
+    fn phones(mut self, field: &str) -> Fields {
+        let bad = self.strings(field).iter().any(|phone| !is_phone(phone));
+        if bad {
+            self.error(field, "malformed phone");
+        }
+        self
+    }
+
+    /// Dates are `YYYY-MM-DD` strings; empty values are allowed.
+    fn date(mut self, field: &str) -> Fields {
+        let bad = self
+            .strings(field)
+            .iter()
+            .any(|date| !date.is_empty() && parse_date(date).is_none());
+        if bad {
+            self.error(field, "impossible date");
+        }
+        self
+    }
+
+    fn date_order(mut self, start: &str, end: &str) -> Fields {
+        let date = |field: &str| {
+            self.value
+                .get(field)
+                .and_then(Value::as_str)
+                .and_then(parse_date)
+        };
+        if let (Some(from), Some(to)) = (date(start), date(end)) {
+            if from > to {
+                self.error(end, "before start date");
+            }
+        }
+        self
+    }
+
+    fn range(mut self, field: &str, min: f64, max: f64) -> Fields {
+        let value = self.value.get(field).and_then(Value::as_f64);
+        if value.map_or(false, |value| value < min || value > max) {
+            self.error(field, "out of range");
+        }
+        self
+    }
+
+    fn finish(self) -> Vec<FieldError> {
+        self.errors
+    }
+}
+
+fn parse_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+fn is_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.chars().any(char::is_whitespace)
+        }
+        None => false,
+    }
+}
+
+/// Phone numbers have 5 to 15 digits, optionally written with `+`, spaces,
+/// dashes and parentheses.
+fn is_phone(phone: &str) -> bool {
+    let digits = phone.chars().filter(char::is_ascii_digit).count();
+    (5..=15).contains(&digits)
+        && phone
+            .chars()
+            .all(|c| c.is_ascii_digit() || "+-() ".contains(c))
+}
+
+impl Validate for Certificate {
+    fn validate(&self) -> Vec<FieldError> {
+        Fields::of(self)
+            .ids()
+            .required("num")
+            .date("cert_date")
+            .finish()
+    }
+}
+
+impl Validate for Company {
+    fn validate(&self) -> Vec<FieldError> {
+        Fields::of(self)
+            .ids()
+            .required("name")
+            .emails("emails")
+            .phones("phones")
+            .phones("faxes")
+            .finish()
+    }
+}
+
+impl Validate for Contact {
+    fn validate(&self) -> Vec<FieldError> {
+        Fields::of(self)
+            .ids()
+            .required("name")
+            .emails("emails")
+            .phones("phones")
+            .phones("faxes")
+            .date("birthday")
+            .finish()
+    }
+}
+
+impl Validate for Department {
+    fn validate(&self) -> Vec<FieldError> {
+        Fields::of(self).ids().required("name").finish()
+    }
+}
+
+impl Validate for Education {
+    fn validate(&self) -> Vec<FieldError> {
+        Fields::of(self)
+            .ids()
+            .date("start_date")
+            .date("end_date")
+            .date_order("start_date", "end_date")
+            .finish()
+    }
+}
+
+impl Validate for Kind {
+    fn validate(&self) -> Vec<FieldError> {
+        Fields::of(self).ids().required("name").finish()
+    }
+}
+
+impl Validate for Post {
+    fn validate(&self) -> Vec<FieldError> {
+        Fields::of(self).ids().required("name").finish()
+    }
+}
+
+impl Validate for Practice {
+    fn validate(&self) -> Vec<FieldError> {
+        Fields::of(self).ids().date("date_of_practice").finish()
+    }
+}
+
+impl Validate for Rank {
+    fn validate(&self) -> Vec<FieldError> {
+        Fields::of(self).ids().required("name").finish()
+    }
+}
+
+impl Validate for Scope {
+    fn validate(&self) -> Vec<FieldError> {
+        Fields::of(self).ids().required("name").finish()
+    }
+}
+
+impl Validate for Siren {
+    fn validate(&self) -> Vec<FieldError> {
+        Fields::of(self)
+            .ids()
+            .range("latitude", -90.0, 90.0)
+            .range("longitude", -180.0, 180.0)
+            .finish()
+    }
+}
+
+impl Validate for SirenType {
+    fn validate(&self) -> Vec<FieldError> {
+        Fields::of(self).ids().required("name").finish()
+    }
+}
+
+impl Validate for User {
+    fn validate(&self) -> Vec<FieldError> {
+        Fields::of(self).ids().required("name").finish()
+    }
+}
+
+impl Validate for DBObject {
+    fn validate(&self) -> Vec<FieldError> {
+        match self {
+            DBObject::Certificate(item) => item.validate(),
+            DBObject::Company(item) => item.validate(),
+            DBObject::Contact(item) => item.validate(),
+            DBObject::Department(item) => item.validate(),
+            DBObject::Education(item) => item.validate(),
+            DBObject::Kind(item) => item.validate(),
+            DBObject::Post(item) => item.validate(),
+            DBObject::Practice(item) => item.validate(),
+            DBObject::Rank(item) => item.validate(),
+            DBObject::Scope(item) => item.validate(),
+            DBObject::Siren(item) => item.validate(),
+            DBObject::SirenType(item) => item.validate(),
+            DBObject::User(item) => item.validate(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+pub fn check(object: &DBObject) -> Result<(), ServiceError> {
+    let errors = object.validate();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ServiceError::Validation(errors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn fields(errors: Vec<FieldError>) -> Vec<String> {
+        errors.into_iter().map(|error| error.field).collect()
+    }
+
+    #[test]
+    fn empty_names_and_negative_ids_are_rejected() {
+        let errors = Fields::from_value(json!({"id": -1, "name": " ", "company_id": 2}))
+            .ids()
+            .required("name")
+            .required("note")
+            .finish();
+        assert_eq!(fields(errors), vec!["id", "name"]);
+    }
+
+    #[test]
+    fn emails_and_phones_are_checked() {
+        assert!(is_email("user@example.com"));
+        assert!(!is_email("user@example"));
+        assert!(!is_email("user example@mail.com"));
+        assert!(!is_email("@mail.com"));
+        assert!(is_phone("+7 (495) 123-45-67"));
+        assert!(is_phone("4951234567"));
+        assert!(!is_phone("12ab34"));
+        assert!(!is_phone("123"));
+        let errors = Fields::from_value(json!({
+            "emails": ["user@example.com", "bad"],
+            "phones": [4951234567_i64],
+        }))
+        .emails("emails")
+        .phones("phones")
+        .finish();
+        assert_eq!(fields(errors), vec!["emails"]);
+    }
+
+    #[test]
+    fn impossible_dates_are_rejected() {
+        let errors = Fields::from_value(json!({
+            "birthday": "2019-02-30",
+            "start_date": "2020-05-01",
+            "end_date": "2020-04-01",
+        }))
+        .date("birthday")
+        .date("start_date")
+        .date_order("start_date", "end_date")
+        .finish();
+        assert_eq!(fields(errors), vec!["birthday", "end_date"]);
+    }
+}